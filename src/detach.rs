@@ -7,6 +7,7 @@ const DFU_DETACH: u8 = 0;
 #[must_use]
 pub struct Detach<T> {
     pub(crate) descriptor: FunctionalDescriptor,
+    pub(crate) timeout: u16,
     pub(crate) chained_command: T,
 }
 
@@ -14,10 +15,37 @@ impl<T> Detach<T> {
     /// Send the command `dfuDETACH` to the device.
     pub fn detach(self) -> (T, UsbWriteControl<[u8; 0]>) {
         log::trace!("Detaching device");
-        let detach_timeout = self.descriptor.detach_timeout;
+        log::trace!("Detach timeout: {}", self.timeout);
+        log::trace!("Device will detach? {}", self.descriptor.will_detach);
         let next = self.chained_command;
-        let control = UsbWriteControl::new(REQUEST_TYPE, DFU_DETACH, detach_timeout, []);
+        let control = UsbWriteControl::new(REQUEST_TYPE, DFU_DETACH, self.timeout, []);
 
         (next, control)
     }
 }
+
+impl<T> ChainedCommand for Detach<T> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<(T, UsbWriteControl<[u8; 0]>), Error>;
+
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        message.check_status()?;
+        if message.state == State::AppIdle {
+            Ok(self.detach())
+        } else {
+            Err(Error::InvalidState {
+                got: message.state,
+                expected: State::AppIdle,
+            })
+        }
+    }
+}
+
+/// The device has been detached from runtime mode.
+///
+/// It must be opened again in DFU mode to download the firmware.
+#[derive(Debug, Clone, Copy)]
+pub struct Detached {
+    /// The device does not detach by itself, the host must issue a USB reset.
+    pub usb_reset: bool,
+}
@@ -0,0 +1,367 @@
+use functional_descriptor::FunctionalDescriptor;
+use pretty_hex::PrettyHex;
+
+use super::*;
+
+const REQUEST_TYPE: u8 = 0b00100001;
+const DFU_DNLOAD: u8 = 1;
+const DFU_UPLOAD: u8 = 2;
+const DFU_ABORT: u8 = 6;
+
+const COMMAND_GET: u8 = 0x00;
+const COMMAND_SET_ADDRESS: u8 = 0x21;
+const COMMAND_ERASE: u8 = 0x41;
+const COMMAND_READ_UNPROTECT: u8 = 0x92;
+
+fn check_idle(message: get_status::GetStatusMessage) -> Result<(), Error> {
+    message.check_status()?;
+    if message.state == State::DfuIdle {
+        Ok(())
+    } else {
+        Err(Error::InvalidState {
+            got: message.state,
+            expected: State::DfuIdle,
+        })
+    }
+}
+
+/// DfuSe commands supported by the device.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Commands {
+    /// Get command (`0x00`).
+    pub get: bool,
+    /// Set Address Pointer command (`0x21`).
+    pub set_address: bool,
+    /// Erase command (`0x41`).
+    pub erase: bool,
+    /// Read Unprotect command (`0x92`).
+    pub read_unprotect: bool,
+}
+
+impl From<&[u8]> for Commands {
+    fn from(bytes: &[u8]) -> Self {
+        let mut commands = Commands::default();
+        for &command in bytes {
+            match command {
+                COMMAND_GET => commands.get = true,
+                COMMAND_SET_ADDRESS => commands.set_address = true,
+                COMMAND_ERASE => commands.erase = true,
+                COMMAND_READ_UNPROTECT => commands.read_unprotect = true,
+                other => log::trace!("Unknown DfuSe command: {:#x}", other),
+            }
+        }
+        commands
+    }
+}
+
+/// Starting point to query the DfuSe commands supported by the device.
+#[must_use]
+pub struct GetCommands<'dfu> {
+    pub(crate) descriptor: &'dfu FunctionalDescriptor,
+}
+
+impl<'dfu> ChainedCommand for GetCommands<'dfu> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<GetCommandsRequest<'dfu>, Error>;
+
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        log::trace!("Starting get commands");
+        check_idle(message)?;
+        Ok(GetCommandsRequest {
+            descriptor: self.descriptor,
+        })
+    }
+}
+
+/// Send the Get command to the device.
+#[must_use]
+pub struct GetCommandsRequest<'dfu> {
+    descriptor: &'dfu FunctionalDescriptor,
+}
+
+impl<'dfu> GetCommandsRequest<'dfu> {
+    /// Send the Get command to the device.
+    pub fn request(
+        self,
+    ) -> (
+        get_status::WaitState<GetCommandsAbort<'dfu>>,
+        UsbWriteControl<[u8; 1]>,
+    ) {
+        let next = get_status::WaitState::new(
+            State::DfuDnbusy,
+            State::DfuDnloadIdle,
+            GetCommandsAbort {
+                descriptor: self.descriptor,
+            },
+        );
+        let control = UsbWriteControl::new(REQUEST_TYPE, DFU_DNLOAD, 0, [COMMAND_GET]);
+
+        (next, control)
+    }
+}
+
+/// Bring the device back to `dfuIDLE` before reading the commands.
+#[must_use]
+pub struct GetCommandsAbort<'dfu> {
+    descriptor: &'dfu FunctionalDescriptor,
+}
+
+impl<'dfu> GetCommandsAbort<'dfu> {
+    /// Abort the download to bring the device back to `dfuIDLE`.
+    pub fn abort(
+        self,
+    ) -> (
+        get_status::WaitState<GetCommandsUpload<'dfu>>,
+        UsbWriteControl<[u8; 0]>,
+    ) {
+        let next = get_status::WaitState::new(
+            State::DfuIdle,
+            State::DfuIdle,
+            GetCommandsUpload {
+                descriptor: self.descriptor,
+            },
+        );
+        let control = UsbWriteControl::new(REQUEST_TYPE, DFU_ABORT, 0, []);
+
+        (next, control)
+    }
+}
+
+/// Read the commands from the device.
+#[must_use]
+pub struct GetCommandsUpload<'dfu> {
+    descriptor: &'dfu FunctionalDescriptor,
+}
+
+impl GetCommandsUpload<'_> {
+    /// Read the commands from the device.
+    ///
+    /// The buffer must be at least as big as the transfer size of the device.
+    pub fn upload(self, buffer: &'_ mut [u8]) -> (GetCommandsRecv, UsbReadControl<'_>) {
+        let len = buffer.len().min(self.descriptor.transfer_size as usize);
+        let control = UsbReadControl::new(REQUEST_TYPE, DFU_UPLOAD, 0, &mut buffer[..len]);
+
+        (GetCommandsRecv, control)
+    }
+}
+
+/// Parse the commands after getting them from the device.
+#[must_use]
+pub struct GetCommandsRecv;
+
+impl GetCommandsRecv {
+    /// Parse the commands and bring the device back to `dfuIDLE`.
+    pub fn chain(
+        self,
+        bytes: &[u8],
+    ) -> (
+        Commands,
+        get_status::GetStatus<get_status::Abort<get_status::WaitState<()>>>,
+    ) {
+        log::trace!("Received commands: {}", bytes.hex_dump());
+        let commands = Commands::from(bytes);
+        log::trace!("Commands: {:?}", commands);
+        let next = get_status::GetStatus {
+            chained_command: get_status::Abort {
+                chained_command: get_status::WaitState::new(State::DfuIdle, State::DfuIdle, ()),
+            },
+        };
+
+        (commands, next)
+    }
+}
+
+/// Starting point to remove the read protection of the device.
+#[must_use]
+pub struct ReadUnprotect;
+
+impl ChainedCommand for ReadUnprotect {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<ReadUnprotectRequest, Error>;
+
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        log::trace!("Starting read unprotect");
+        check_idle(message)?;
+        Ok(ReadUnprotectRequest)
+    }
+}
+
+/// Send the Read Unprotect command to the device.
+#[must_use]
+pub struct ReadUnprotectRequest;
+
+impl ReadUnprotectRequest {
+    /// Send the Read Unprotect command to the device.
+    ///
+    /// The device starts erasing its memory when its status is queried, then resets by itself.
+    /// It may disconnect before answering.
+    pub fn request(
+        self,
+    ) -> (
+        get_status::GetStatus<ReadUnprotectStarted>,
+        UsbWriteControl<[u8; 1]>,
+    ) {
+        let next = get_status::GetStatus {
+            chained_command: ReadUnprotectStarted,
+        };
+        let control = UsbWriteControl::new(
+            REQUEST_TYPE,
+            DFU_DNLOAD,
+            0,
+            <[u8; 1]>::from(download::DownloadCommandReadUnprotect),
+        );
+
+        (next, control)
+    }
+}
+
+/// Check that the device started to remove the read protection.
+#[must_use]
+pub struct ReadUnprotectStarted;
+
+impl ChainedCommand for ReadUnprotectStarted {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<(), Error>;
+
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        message.check_status()?;
+        if message.state == State::DfuDnbusy {
+            log::trace!("Read unprotect started, the device will reset");
+            Ok(())
+        } else {
+            Err(Error::InvalidState {
+                got: message.state,
+                expected: State::DfuDnbusy,
+            })
+        }
+    }
+}
+
+/// Starting point to erase the whole memory of the device.
+#[must_use]
+pub struct MassErase;
+
+impl ChainedCommand for MassErase {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<MassEraseRequest, Error>;
+
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        log::trace!("Starting mass erase");
+        check_idle(message)?;
+        Ok(MassEraseRequest)
+    }
+}
+
+/// Send the mass erase command to the device.
+#[must_use]
+pub struct MassEraseRequest;
+
+impl MassEraseRequest {
+    /// Send the mass erase command to the device.
+    ///
+    /// Erasing can take several seconds, the poll timeout of the device must be honoured while
+    /// waiting. The device is left in `dfuDNLOAD-IDLE`.
+    pub fn request(self) -> (get_status::WaitState<()>, UsbWriteControl<[u8; 1]>) {
+        let next = get_status::WaitState::new(State::DfuDnbusy, State::DfuDnloadIdle, ());
+        let control = UsbWriteControl::new(
+            REQUEST_TYPE,
+            DFU_DNLOAD,
+            0,
+            <[u8; 1]>::from(download::DownloadCommandMassErase),
+        );
+
+        (next, control)
+    }
+}
+
+/// Starting point to leave DFU mode and jump to an address.
+#[must_use]
+pub struct Leave {
+    pub(crate) address: u32,
+}
+
+impl ChainedCommand for Leave {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<LeaveSetAddress, Error>;
+
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        log::trace!("Starting leave");
+        check_idle(message)?;
+        Ok(LeaveSetAddress {
+            address: self.address,
+        })
+    }
+}
+
+/// Set the address to jump to when leaving DFU mode.
+#[must_use]
+pub struct LeaveSetAddress {
+    pub(crate) address: u32,
+}
+
+impl LeaveSetAddress {
+    /// Set the address to jump to when leaving DFU mode.
+    pub fn set_address(
+        self,
+    ) -> (
+        get_status::WaitState<LeaveRequest>,
+        UsbWriteControl<[u8; 5]>,
+    ) {
+        log::trace!("Leave address: {:#x}", self.address);
+        let next = get_status::WaitState::new(State::DfuDnbusy, State::DfuDnloadIdle, LeaveRequest);
+        let control = UsbWriteControl::new(
+            REQUEST_TYPE,
+            DFU_DNLOAD,
+            0,
+            <[u8; 5]>::from(download::DownloadCommandSetAddress(self.address)),
+        );
+
+        (next, control)
+    }
+}
+
+/// Send the zero-length download that makes the device leave DFU mode.
+#[must_use]
+pub struct LeaveRequest;
+
+impl LeaveRequest {
+    /// Send the zero-length download that makes the device leave DFU mode.
+    ///
+    /// The device jumps to the address when its status is queried. It may disconnect before
+    /// answering.
+    pub fn request(
+        self,
+    ) -> (
+        get_status::GetStatus<LeaveStarted>,
+        UsbWriteControl<[u8; 0]>,
+    ) {
+        let next = get_status::GetStatus {
+            chained_command: LeaveStarted,
+        };
+        let control = UsbWriteControl::new(REQUEST_TYPE, DFU_DNLOAD, 2, []);
+
+        (next, control)
+    }
+}
+
+/// Check that the device is leaving DFU mode.
+#[must_use]
+pub struct LeaveStarted;
+
+impl ChainedCommand for LeaveStarted {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<(), Error>;
+
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        message.check_status()?;
+        if message.state == State::DfuManifest {
+            log::trace!("Device is leaving DFU mode");
+            Ok(())
+        } else {
+            Err(Error::InvalidState {
+                got: message.state,
+                expected: State::DfuManifest,
+            })
+        }
+    }
+}
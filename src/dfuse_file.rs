@@ -0,0 +1,556 @@
+use bytes::Buf;
+use displaydoc::Display;
+#[cfg(any(feature = "std", test))]
+use std::prelude::v1::*;
+#[cfg(any(feature = "std", test))]
+use thiserror::Error;
+
+/// Signature at the start of a DfuSe file.
+pub const PREFIX_SIGNATURE: &[u8; 5] = b"DfuSe";
+/// Signature at the start of a target.
+pub const TARGET_SIGNATURE: &[u8; 6] = b"Target";
+/// Version of the DfuSe file format.
+pub const VERSION: u8 = 0x01;
+
+const PREFIX_SIZE: usize = 11;
+const TARGET_PREFIX_SIZE: usize = 274;
+const TARGET_NAME_SIZE: usize = 255;
+const ELEMENT_HEADER_SIZE: usize = 8;
+
+/// Error when reading a DfuSe file.
+#[derive(Debug, Display)]
+#[cfg_attr(any(feature = "std", test), derive(Error))]
+#[allow(missing_docs)]
+pub enum Error {
+    /// The data is too short (got: {got}, expected: {expected}).
+    DataTooShort { got: usize, expected: usize },
+    /// The DfuSe prefix signature is invalid.
+    InvalidPrefixSignature,
+    /// Unsupported DfuSe file version: {0}.
+    UnsupportedVersion(u8),
+    /// The target signature at offset {offset:#x} is invalid.
+    InvalidTargetSignature { offset: usize },
+    /// The size of the target at offset {offset:#x} does not match its elements.
+    InvalidTargetSize { offset: usize },
+    /// The image size does not match the targets (got: {got}, expected: {expected}).
+    InvalidImageSize { got: usize, expected: usize },
+}
+
+/// A DfuSe file.
+///
+/// The file is made of a prefix, a list of targets (one per alternate setting of the interface)
+/// each holding image elements to write at an address, and the DFU suffix. The suffix is not
+/// part of the image and is ignored by the parser.
+#[derive(Debug, Clone, Copy)]
+pub struct DfuseFile<'a> {
+    version: u8,
+    targets_count: u8,
+    targets: &'a [u8],
+}
+
+impl<'a> DfuseFile<'a> {
+    /// Parse a DfuSe file.
+    ///
+    /// The whole structure of the file is checked, the targets and the elements can then be read
+    /// without error.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        let mut prefix = take(bytes, 0, PREFIX_SIZE)?;
+        if &prefix[..PREFIX_SIGNATURE.len()] != PREFIX_SIGNATURE {
+            return Err(Error::InvalidPrefixSignature);
+        }
+        prefix.advance(PREFIX_SIGNATURE.len());
+        let version = prefix.get_u8();
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let image_size = prefix.get_u32_le() as usize;
+        let targets_count = prefix.get_u8();
+        log::trace!("DfuSe file image size: {}", image_size);
+        log::trace!("DfuSe file targets: {}", targets_count);
+
+        let image = take(bytes, 0, image_size)?;
+        let mut offset = PREFIX_SIZE;
+        for _ in 0..targets_count {
+            let target = take(image, offset, TARGET_PREFIX_SIZE)?;
+            if &target[..TARGET_SIGNATURE.len()] != TARGET_SIGNATURE {
+                return Err(Error::InvalidTargetSignature { offset });
+            }
+            let (target_size, elements_count) = target_sizes(target);
+            let elements_offset = offset + TARGET_PREFIX_SIZE;
+            let mut elements = take(image, elements_offset, target_size)?;
+
+            for _ in 0..elements_count {
+                if elements.len() < ELEMENT_HEADER_SIZE {
+                    return Err(Error::InvalidTargetSize { offset });
+                }
+                elements.advance(4);
+                let size = elements.get_u32_le() as usize;
+                if elements.len() < size {
+                    return Err(Error::InvalidTargetSize { offset });
+                }
+                elements.advance(size);
+            }
+            if !elements.is_empty() {
+                return Err(Error::InvalidTargetSize { offset });
+            }
+
+            offset = elements_offset + target_size;
+        }
+
+        if offset != image_size {
+            return Err(Error::InvalidImageSize {
+                got: image_size,
+                expected: offset,
+            });
+        }
+
+        Ok(Self {
+            version,
+            targets_count,
+            targets: &image[PREFIX_SIZE..],
+        })
+    }
+
+    /// Version of the DfuSe file format.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Number of targets in the file.
+    pub fn targets_count(&self) -> u8 {
+        self.targets_count
+    }
+
+    /// Iterate over the targets of the file.
+    pub fn targets(&self) -> Targets<'a> {
+        Targets {
+            data: self.targets,
+            remaining: self.targets_count,
+        }
+    }
+
+    /// Returns the first target for this alternate setting.
+    pub fn target(&self, alt_setting: u8) -> Option<Target<'a>> {
+        self.targets()
+            .find(|target| target.alt_setting() == alt_setting)
+    }
+}
+
+/// Returns `len` bytes at `offset`.
+fn take(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], Error> {
+    let end = offset.saturating_add(len);
+    bytes.get(offset..end).ok_or(Error::DataTooShort {
+        got: bytes.len(),
+        expected: end,
+    })
+}
+
+/// Returns the size and the number of elements of a target from its prefix.
+fn target_sizes(mut target: &[u8]) -> (usize, u32) {
+    target.advance(TARGET_PREFIX_SIZE - 8);
+    (target.get_u32_le() as usize, target.get_u32_le())
+}
+
+/// Iterator over the targets of a DfuSe file.
+#[derive(Debug, Clone)]
+pub struct Targets<'a> {
+    data: &'a [u8],
+    remaining: u8,
+}
+
+impl<'a> Iterator for Targets<'a> {
+    type Item = Target<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let (prefix, rest) = self.data.split_at(TARGET_PREFIX_SIZE);
+        let (target_size, elements_count) = target_sizes(prefix);
+        let (elements, rest) = rest.split_at(target_size);
+        self.data = rest;
+
+        let mut prefix = &prefix[TARGET_SIGNATURE.len()..];
+        let alt_setting = prefix.get_u8();
+        let named = prefix.get_u32_le() != 0;
+        let name = &prefix[..TARGET_NAME_SIZE];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+
+        Some(Target {
+            alt_setting,
+            name: named.then_some(name),
+            elements_count,
+            elements,
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+/// A target of a DfuSe file, holding the image elements for an alternate setting.
+#[derive(Debug, Clone, Copy)]
+pub struct Target<'a> {
+    alt_setting: u8,
+    name: Option<&'a [u8]>,
+    elements_count: u32,
+    elements: &'a [u8],
+}
+
+impl<'a> Target<'a> {
+    /// Alternate setting of the interface this target is for.
+    pub fn alt_setting(&self) -> u8 {
+        self.alt_setting
+    }
+
+    /// Name of the target.
+    ///
+    /// Returns `None` if the target is not named or if the name is not valid UTF-8.
+    pub fn name(&self) -> Option<&'a str> {
+        self.name.and_then(|name| core::str::from_utf8(name).ok())
+    }
+
+    /// Number of image elements in the target.
+    pub fn elements_count(&self) -> u32 {
+        self.elements_count
+    }
+
+    /// Iterate over the image elements of the target.
+    pub fn elements(&self) -> Elements<'a> {
+        Elements {
+            data: self.elements,
+            remaining: self.elements_count,
+        }
+    }
+}
+
+/// Iterator over the image elements of a target.
+#[derive(Debug, Clone)]
+pub struct Elements<'a> {
+    data: &'a [u8],
+    remaining: u32,
+}
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = Element<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let address = self.data.get_u32_le();
+        let size = self.data.get_u32_le() as usize;
+        let (data, rest) = self.data.split_at(size);
+        self.data = rest;
+
+        Some(Element { address, data })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+/// An image element: data to write at an address.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Element<'a> {
+    /// Start address of the element.
+    pub address: u32,
+    /// Data of the element.
+    pub data: &'a [u8],
+}
+
+/// Writer of DfuSe files.
+///
+/// The file is written without the DFU suffix.
+#[cfg(any(feature = "std", test))]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[derive(Debug, Default)]
+pub struct Writer<'a> {
+    targets: Vec<WriterTarget<'a>>,
+}
+
+#[cfg(any(feature = "std", test))]
+#[derive(Debug)]
+struct WriterTarget<'a> {
+    alt_setting: u8,
+    name: Option<&'a str>,
+    elements: Vec<Element<'a>>,
+}
+
+#[cfg(any(feature = "std", test))]
+impl<'a> Writer<'a> {
+    /// Create a new instance of [`Writer`] without target.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a target for an alternate setting with its image elements.
+    pub fn add_target(
+        &mut self,
+        alt_setting: u8,
+        name: Option<&'a str>,
+        elements: impl IntoIterator<Item = Element<'a>>,
+    ) -> &mut Self {
+        self.targets.push(WriterTarget {
+            alt_setting,
+            name,
+            elements: elements.into_iter().collect(),
+        });
+        self
+    }
+
+    /// Write the DfuSe file.
+    ///
+    /// Fails with [`std::io::ErrorKind::InvalidInput`] before writing anything if a target name
+    /// is longer than 255 bytes or if the file is too big.
+    pub fn write<W: std::io::Write>(&self, mut writer: W) -> std::io::Result<()> {
+        use bytes::BufMut;
+        use std::io::{Error, ErrorKind};
+
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidInput, message);
+
+        let targets_count =
+            u8::try_from(self.targets.len()).map_err(|_| invalid("too many targets"))?;
+        // Validate every target before writing anything
+        let mut target_sizes = Vec::with_capacity(self.targets.len());
+        let mut image_size = PREFIX_SIZE as u64;
+        for target in &self.targets {
+            if target.name.unwrap_or_default().len() > TARGET_NAME_SIZE {
+                return Err(invalid("target name too long"));
+            }
+            let elements_count =
+                u32::try_from(target.elements.len()).map_err(|_| invalid("too many elements"))?;
+            let target_size: u64 = target
+                .elements
+                .iter()
+                .map(|element| (ELEMENT_HEADER_SIZE + element.data.len()) as u64)
+                .sum();
+            let target_size = u32::try_from(target_size).map_err(|_| invalid("target too big"))?;
+            target_sizes.push((target_size, elements_count));
+            image_size += TARGET_PREFIX_SIZE as u64 + target_size as u64;
+        }
+        let image_size = u32::try_from(image_size).map_err(|_| invalid("image too big"))?;
+
+        let mut prefix = Vec::with_capacity(PREFIX_SIZE);
+        prefix.put_slice(PREFIX_SIGNATURE);
+        prefix.put_u8(VERSION);
+        prefix.put_u32_le(image_size);
+        prefix.put_u8(targets_count);
+        writer.write_all(&prefix)?;
+
+        for (target, (target_size, elements_count)) in self.targets.iter().zip(target_sizes) {
+            let name = target.name.unwrap_or_default().as_bytes();
+
+            let mut prefix = Vec::with_capacity(TARGET_PREFIX_SIZE);
+            prefix.put_slice(TARGET_SIGNATURE);
+            prefix.put_u8(target.alt_setting);
+            prefix.put_u32_le(target.name.is_some().into());
+            prefix.put_slice(name);
+            prefix.put_bytes(0, TARGET_NAME_SIZE - name.len());
+            prefix.put_u32_le(target_size);
+            prefix.put_u32_le(elements_count);
+            writer.write_all(&prefix)?;
+
+            for element in &target.elements {
+                let mut header = Vec::with_capacity(ELEMENT_HEADER_SIZE);
+                header.put_u32_le(element.address);
+                // The size fits as the target size does
+                header.put_u32_le(element.data.len() as u32);
+                writer.write_all(&header)?;
+                writer.write_all(element.data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the DfuSe file into a vector.
+    ///
+    /// # Panics
+    ///
+    /// Panics if [`Self::write`] refuses the file.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes).expect("invalid DfuSe file");
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"DfuSe\x01");
+        bytes.extend_from_slice(&(11u32 + 274 + 8 + 4 + 8 + 2).to_le_bytes());
+        bytes.push(1);
+        bytes.extend_from_slice(b"Target\x00");
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        let mut name = [0; 255];
+        name[..2].copy_from_slice(b"ST");
+        bytes.extend_from_slice(&name);
+        bytes.extend_from_slice(&(8u32 + 4 + 8 + 2).to_le_bytes());
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&0x08000000u32.to_le_bytes());
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(&[1, 2, 3, 4]);
+        bytes.extend_from_slice(&0x08040000u32.to_le_bytes());
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&[5, 6]);
+        bytes
+    }
+
+    #[test]
+    fn parsing() {
+        let mut bytes = example();
+        // The DFU suffix is ignored
+        bytes.extend_from_slice(&[0xff; 16]);
+        let file = DfuseFile::parse(&bytes).unwrap();
+        assert_eq!(file.version(), 1);
+        assert_eq!(file.targets_count(), 1);
+
+        let target = file.target(0).unwrap();
+        assert_eq!(target.name(), Some("ST"));
+        assert_eq!(target.elements_count(), 2);
+        let elements: Vec<_> = target.elements().collect();
+        assert_eq!(
+            elements,
+            &[
+                Element {
+                    address: 0x08000000,
+                    data: &[1, 2, 3, 4],
+                },
+                Element {
+                    address: 0x08040000,
+                    data: &[5, 6],
+                },
+            ]
+        );
+        assert!(file.target(1).is_none());
+    }
+
+    #[test]
+    fn writing() {
+        let bytes = Writer::new()
+            .add_target(
+                0,
+                Some("ST"),
+                [
+                    Element {
+                        address: 0x08000000,
+                        data: &[1, 2, 3, 4],
+                    },
+                    Element {
+                        address: 0x08040000,
+                        data: &[5, 6],
+                    },
+                ],
+            )
+            .to_vec();
+        assert_eq!(bytes, example());
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = Writer::new()
+            .add_target(0, None, [])
+            .add_target(
+                1,
+                Some("Option Bytes"),
+                [Element {
+                    address: 0x1fff7800,
+                    data: &[0xaa; 16],
+                }],
+            )
+            .to_vec();
+        let file = DfuseFile::parse(&bytes).unwrap();
+        let targets: Vec<_> = file.targets().collect();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].alt_setting(), 0);
+        assert_eq!(targets[0].name(), None);
+        assert_eq!(targets[0].elements().count(), 0);
+        assert_eq!(targets[1].alt_setting(), 1);
+        assert_eq!(targets[1].name(), Some("Option Bytes"));
+        assert_eq!(
+            targets[1].elements().next(),
+            Some(Element {
+                address: 0x1fff7800,
+                data: &[0xaa; 16],
+            })
+        );
+    }
+
+    #[test]
+    fn writing_errors() {
+        let name = "x".repeat(256);
+        let mut writer = Writer::new();
+        writer
+            .add_target(0, Some("ST"), [])
+            .add_target(1, Some(&name), []);
+        let mut bytes = Vec::new();
+        let err = writer.write(&mut bytes).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn parsing_errors() {
+        let bytes = example();
+        assert!(matches!(
+            DfuseFile::parse(&bytes[..5]),
+            Err(Error::DataTooShort {
+                got: 5,
+                expected: 11
+            })
+        ));
+        assert!(matches!(
+            DfuseFile::parse(&bytes[..bytes.len() - 1]),
+            Err(Error::DataTooShort { .. })
+        ));
+
+        let mut invalid = bytes.clone();
+        invalid[0] = b'X';
+        assert!(matches!(
+            DfuseFile::parse(&invalid),
+            Err(Error::InvalidPrefixSignature)
+        ));
+
+        let mut invalid = bytes.clone();
+        invalid[5] = 2;
+        assert!(matches!(
+            DfuseFile::parse(&invalid),
+            Err(Error::UnsupportedVersion(2))
+        ));
+
+        let mut invalid = bytes.clone();
+        invalid[11] = b'X';
+        assert!(matches!(
+            DfuseFile::parse(&invalid),
+            Err(Error::InvalidTargetSignature { offset: 11 })
+        ));
+
+        // One element more than in the target
+        let mut invalid = bytes.clone();
+        invalid[11 + 270] = 3;
+        assert!(matches!(
+            DfuseFile::parse(&invalid),
+            Err(Error::InvalidTargetSize { offset: 11 })
+        ));
+
+        // Trailing data in the image
+        let mut invalid = bytes.clone();
+        invalid.push(0);
+        invalid[6] += 1;
+        assert!(matches!(
+            DfuseFile::parse(&invalid),
+            Err(Error::InvalidImageSize { .. })
+        ));
+    }
+}
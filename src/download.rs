@@ -11,24 +11,20 @@ pub struct Start<'dfu> {
     pub(crate) descriptor: &'dfu FunctionalDescriptor,
     pub(crate) end_pos: u32,
     pub(crate) protocol: ProtocolData<'dfu>,
+    pub(crate) detach: Option<detach::Detach<detach::Detached>>,
 }
 
 impl<'dfu> ChainedCommand for Start<'dfu> {
     type Arg = get_status::GetStatusMessage;
     type Into = Result<DownloadLoop<'dfu>, Error>;
 
-    fn chain(
-        self,
-        get_status::GetStatusMessage {
-            status: _,
-            poll_timeout: _,
-            state,
-            index: _,
-        }: Self::Arg,
-    ) -> Self::Into {
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        message.check_status()?;
+        let state = message.state;
         log::trace!("Starting download process");
-        // TODO startup can be in AppIdle in which case the Detach-Attach process needs to be done
-        if state == State::DfuIdle {
+        // In appIDLE the device must first be detached to enter DFU mode
+        let detach = self.detach.filter(|_| state == State::AppIdle);
+        if state == State::DfuIdle || detach.is_some() {
             let (block_num, copied_pos) = match self.protocol {
                 ProtocolData::Dfu => (0, 0),
                 ProtocolData::Dfuse(d) => (2, d.address),
@@ -40,6 +36,7 @@ impl<'dfu> ChainedCommand for Start<'dfu> {
                 protocol: self.protocol,
                 block_num,
                 eof: false,
+                detach,
             })
         } else {
             Err(Error::InvalidState {
@@ -53,9 +50,51 @@ impl<'dfu> ChainedCommand for Start<'dfu> {
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct DfuseProtocolData<'dfu> {
     pub address: u32,
+    /// Address of the first segment.
+    pub start_address: u32,
     pub erased_pos: u32,
+    /// Offset of the addresses on the device from the addresses of the pages.
+    pub offset: u32,
+    pub mass_erase: bool,
     pub address_set: bool,
+    /// Pages that remain to be erased.
     pub memory_layout: &'dfu memory_layout::mem,
+    /// The whole memory layout.
+    pub layout: &'dfu memory_layout::mem,
+}
+
+/// Check that the range can be downloaded and returns the pages from the one containing the
+/// address.
+///
+/// The pages must be erasable (unless the memory is mass erased) and writeable. This is checked
+/// before anything is erased or written. The addresses of the pages are shifted by `offset`.
+pub(crate) fn check_range(
+    layout: &memory_layout::mem,
+    offset: u32,
+    address: u32,
+    length: u32,
+    mass_erase: bool,
+) -> Result<&memory_layout::mem, Error> {
+    let out_of_range = || Error::AddressOutOfRange { address, length };
+    let layout_address = address.checked_sub(offset).ok_or_else(out_of_range)?;
+    if !memory_layout::contains_range(layout, layout_address, length) {
+        return Err(out_of_range());
+    }
+    let attributes = if mass_erase {
+        memory_layout::Attributes::WRITEABLE
+    } else {
+        memory_layout::Attributes::ERASABLE | memory_layout::Attributes::WRITEABLE
+    };
+    for page in memory_layout::pages_in_range(layout, layout_address, length) {
+        if let Some(attribute) = attributes.missing_from(page.attributes) {
+            return Err(Error::MissingPageAttribute {
+                address: page.address.wrapping_add(offset),
+                attribute,
+            });
+        }
+    }
+
+    memory_layout::from_address(layout, layout_address).ok_or_else(out_of_range)
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -73,11 +112,17 @@ pub struct DownloadLoop<'dfu> {
     copied_pos: u32,
     block_num: u16,
     eof: bool,
+    detach: Option<detach::Detach<detach::Detached>>,
 }
 
 impl<'dfu> DownloadLoop<'dfu> {
     /// Get the next step in the download loop.
     pub fn next(self) -> Step<'dfu> {
+        if let Some(detach) = self.detach {
+            log::trace!("Download loop: detach device");
+            return Step::Detach(detach);
+        }
+
         if self.eof {
             log::trace!("Download loop ended");
 
@@ -92,6 +137,16 @@ impl<'dfu> DownloadLoop<'dfu> {
         }
 
         match self.protocol {
+            ProtocolData::Dfuse(d) if d.mass_erase && d.erased_pos < self.end_pos => {
+                log::trace!("Download loop: mass erase");
+                Step::EraseAll(EraseAll {
+                    descriptor: self.descriptor,
+                    end_pos: self.end_pos,
+                    copied_pos: self.copied_pos,
+                    protocol: d,
+                    block_num: self.block_num,
+                })
+            }
             ProtocolData::Dfuse(d) if d.erased_pos < self.end_pos => {
                 log::trace!("Download loop: erase page");
                 log::trace!("Erased position: {}", d.erased_pos);
@@ -133,7 +188,10 @@ impl<'dfu> DownloadLoop<'dfu> {
 pub enum Step<'dfu> {
     Break,
     UsbReset,
+    /// The device is in runtime mode and must be detached, the download cannot continue.
+    Detach(detach::Detach<detach::Detached>),
     Erase(ErasePage<'dfu>),
+    EraseAll(EraseAll<'dfu>),
     SetAddress(SetAddress<'dfu>),
     DownloadChunk(DownloadChunk<'dfu>),
 }
@@ -159,19 +217,19 @@ impl<'dfu> ErasePage<'dfu> {
         ),
         crate::Error,
     > {
-        let (&page_size, rest_memory_layout) = self
+        let (page, rest_memory_layout) = self
             .protocol
             .memory_layout
             .split_first()
             .ok_or(Error::NoSpaceLeft)?;
         log::trace!("Rest of memory layout: {:?}", rest_memory_layout);
-        log::trace!("Page size: {:?}", page_size);
+        log::trace!("Page address: {:#x}", page.address);
+        log::trace!("Page size: {:?}", page.size);
 
+        let page_address = page.address.wrapping_add(self.protocol.offset);
         let next_protocol = ProtocolData::Dfuse(DfuseProtocolData {
-            erased_pos: self
-                .protocol
-                .erased_pos
-                .checked_add(page_size)
+            erased_pos: page_address
+                .checked_add(page.size)
                 .ok_or(Error::EraseLimitReached)?,
             memory_layout: rest_memory_layout,
             ..self.protocol
@@ -186,6 +244,7 @@ impl<'dfu> ErasePage<'dfu> {
                 copied_pos: self.copied_pos,
                 block_num: self.block_num,
                 eof: false,
+                detach: None,
             },
         );
 
@@ -193,13 +252,62 @@ impl<'dfu> ErasePage<'dfu> {
             request_type: REQUEST_TYPE,
             request: DFU_DNLOAD,
             value: 0,
-            buffer: <[u8; 5]>::from(DownloadCommandErase(self.protocol.erased_pos)),
+            buffer: <[u8; 5]>::from(DownloadCommandErase(page_address)),
         };
 
         Ok((next, control))
     }
 }
 
+/// Erase the whole memory at once.
+#[must_use]
+pub struct EraseAll<'dfu> {
+    descriptor: &'dfu FunctionalDescriptor,
+    end_pos: u32,
+    copied_pos: u32,
+    protocol: DfuseProtocolData<'dfu>,
+    block_num: u16,
+}
+
+impl<'dfu> EraseAll<'dfu> {
+    /// Erase the whole memory at once.
+    ///
+    /// Erasing can take several seconds, the poll timeout of the device must be honoured while
+    /// waiting.
+    pub fn erase(
+        self,
+    ) -> (
+        get_status::WaitState<DownloadLoop<'dfu>>,
+        UsbWriteControl<[u8; 1]>,
+    ) {
+        let next_protocol = ProtocolData::Dfuse(DfuseProtocolData {
+            erased_pos: self.end_pos,
+            ..self.protocol
+        });
+        let next = get_status::WaitState::new(
+            State::DfuDnbusy,
+            State::DfuDnloadIdle,
+            DownloadLoop {
+                descriptor: self.descriptor,
+                protocol: next_protocol,
+                end_pos: self.end_pos,
+                copied_pos: self.copied_pos,
+                block_num: self.block_num,
+                eof: false,
+                detach: None,
+            },
+        );
+        let control = UsbWriteControl::new(
+            REQUEST_TYPE,
+            DFU_DNLOAD,
+            0,
+            <[u8; 1]>::from(DownloadCommandMassErase),
+        );
+
+        (next, control)
+    }
+}
+
 /// Set the address for download.
 #[must_use]
 pub struct SetAddress<'dfu> {
@@ -233,13 +341,14 @@ impl<'dfu> SetAddress<'dfu> {
                 protocol: next_protocol,
                 block_num: self.block_num,
                 eof: false,
+                detach: None,
             },
         );
         let control = UsbWriteControl::new(
             REQUEST_TYPE,
             DFU_DNLOAD,
             0,
-            <[u8; 5]>::from(DownloadCommandSetAddress(self.copied_pos)),
+            <[u8; 5]>::from(DownloadCommandSetAddress(self.protocol.address)),
         );
 
         (next, control)
@@ -303,6 +412,7 @@ impl<'dfu> DownloadChunk<'dfu> {
                 protocol: self.protocol,
                 block_num: self.block_num.wrapping_add(1),
                 eof: bytes.is_empty(),
+                detach: None,
             },
         );
         let control = UsbWriteControl {
@@ -314,11 +424,111 @@ impl<'dfu> DownloadChunk<'dfu> {
 
         Ok((next, control))
     }
+
+    /// Leave DFU mode instead of downloading this chunk, jumping to the start address of the
+    /// first segment.
+    ///
+    /// Returns `None` if the device does not use DfuSe.
+    pub fn leave(self) -> Option<dfuse::LeaveSetAddress> {
+        match self.protocol {
+            ProtocolData::Dfu => None,
+            ProtocolData::Dfuse(d) => Some(dfuse::LeaveSetAddress {
+                address: d.start_address,
+            }),
+        }
+    }
+
+    /// Leave DFU mode instead of downloading this chunk, jumping to `address`.
+    ///
+    /// Returns `None` if the device does not use DfuSe.
+    pub fn leave_at(self, address: u32) -> Option<dfuse::LeaveSetAddress> {
+        match self.protocol {
+            ProtocolData::Dfu => None,
+            ProtocolData::Dfuse(_) => Some(dfuse::LeaveSetAddress { address }),
+        }
+    }
+
+    /// Continue with another segment of `length` bytes at `address` instead of downloading this
+    /// chunk, in the same session.
+    ///
+    /// The segments must be sorted by address and must not overlap, which can be checked for all
+    /// of them before the download with [`DfuSansIo::check_segments`]. Only the pages overlapping
+    /// the new segment that have not been erased for a previous segment are erased. The device
+    /// manifests once after the last segment.
+    pub fn next_segment(self, address: u32, length: u32) -> Result<DownloadLoop<'dfu>, Error> {
+        let ProtocolData::Dfuse(d) = self.protocol else {
+            return Err(Error::DfuseNotSupported);
+        };
+        if address < self.copied_pos {
+            return Err(Error::OverlappingSegments { address });
+        }
+        let end_pos = address.checked_add(length).ok_or(Error::NoSpaceLeft)?;
+        let memory_layout = check_range(d.layout, d.offset, address, length, d.mass_erase)?;
+        log::trace!("Next segment: {:#x} ({} bytes)", address, length);
+
+        let page_address = memory_layout[0].address.wrapping_add(d.offset);
+        let (erased_pos, memory_layout) = if d.mass_erase {
+            // Everything has been erased with the first segment
+            (end_pos, memory_layout)
+        } else if d.erased_pos > page_address {
+            // The first page has been erased with the previous segment
+            (
+                d.erased_pos,
+                memory_layout::from_address(d.layout, d.erased_pos.wrapping_sub(d.offset))
+                    .unwrap_or_default(),
+            )
+        } else {
+            (page_address, memory_layout)
+        };
+
+        Ok(DownloadLoop {
+            descriptor: self.descriptor,
+            protocol: ProtocolData::Dfuse(DfuseProtocolData {
+                address,
+                erased_pos,
+                address_set: false,
+                memory_layout,
+                ..d
+            }),
+            end_pos,
+            copied_pos: address,
+            block_num: 2,
+            eof: false,
+            detach: None,
+        })
+    }
+
+    /// Set the DfuSe address pointer to the start address of the first segment again before the
+    /// last, empty, chunk that manifests the firmware.
+    ///
+    /// This is needed when the device left `dfuDNLOAD-IDLE` in between, for example after reading
+    /// back the downloaded data, or when other segments were downloaded with
+    /// [`Self::next_segment`]. It does nothing if the device does not use DfuSe.
+    pub fn reset_address(self) -> DownloadLoop<'dfu> {
+        let protocol = match self.protocol {
+            ProtocolData::Dfu => ProtocolData::Dfu,
+            ProtocolData::Dfuse(d) => ProtocolData::Dfuse(DfuseProtocolData {
+                address: d.start_address,
+                address_set: false,
+                ..d
+            }),
+        };
+
+        DownloadLoop {
+            descriptor: self.descriptor,
+            protocol,
+            end_pos: self.end_pos,
+            copied_pos: self.copied_pos,
+            block_num: self.block_num,
+            eof: false,
+            detach: None,
+        }
+    }
 }
 
 /// Command to erase.
 #[derive(Debug, Clone, Copy)]
-pub struct DownloadCommandErase(u32);
+pub struct DownloadCommandErase(pub(crate) u32);
 
 impl From<DownloadCommandErase> for [u8; 5] {
     fn from(command: DownloadCommandErase) -> Self {
@@ -329,9 +539,29 @@ impl From<DownloadCommandErase> for [u8; 5] {
     }
 }
 
+/// Command to erase the whole memory.
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadCommandMassErase;
+
+impl From<DownloadCommandMassErase> for [u8; 1] {
+    fn from(_command: DownloadCommandMassErase) -> Self {
+        [0x41]
+    }
+}
+
+/// Command to remove the read protection of the device.
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadCommandReadUnprotect;
+
+impl From<DownloadCommandReadUnprotect> for [u8; 1] {
+    fn from(_command: DownloadCommandReadUnprotect) -> Self {
+        [0x92]
+    }
+}
+
 /// Command to set address to download.
 #[derive(Debug, Clone, Copy)]
-pub struct DownloadCommandSetAddress(u32);
+pub struct DownloadCommandSetAddress(pub(crate) u32);
 
 impl From<DownloadCommandSetAddress> for [u8; 5] {
     fn from(command: DownloadCommandSetAddress) -> Self {
@@ -4,9 +4,12 @@ use pretty_hex::PrettyHex;
 
 const REQUEST_TYPE: u8 = 0b00100001;
 const DFU_GETSTATUS: u8 = 3;
-const DFU_CLRSTATUS: u8 = 4;
+pub(crate) const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+pub(crate) const DFU_ABORT: u8 = 6;
 
 /// Get status message.
+#[derive(Debug, Clone, Copy)]
 pub struct GetStatusMessage {
     /// Status.
     pub status: Status,
@@ -18,6 +21,22 @@ pub struct GetStatusMessage {
     pub index: u8,
 }
 
+impl GetStatusMessage {
+    /// Returns an error if the device reports a status other than [`Status::Ok`].
+    pub fn check_status(&self) -> Result<(), Error> {
+        if self.status == Status::Ok {
+            Ok(())
+        } else {
+            log::trace!("Device status is in error: {:?}", self.status);
+            Err(Error::StatusError {
+                status: self.status,
+                state: self.state,
+                index: self.index,
+            })
+        }
+    }
+}
+
 /// Command that queries the status of the device.
 #[must_use]
 pub struct GetStatus<T: ChainedCommand<Arg = GetStatusMessage>> {
@@ -74,6 +93,54 @@ impl<T: ChainedCommand<Arg = GetStatusMessage>> GetStatusRecv<T> {
     }
 }
 
+/// Command that returns the status of the device as is.
+#[must_use]
+pub struct ReturnStatus;
+
+impl ChainedCommand for ReturnStatus {
+    type Arg = GetStatusMessage;
+    type Into = GetStatusMessage;
+
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        message
+    }
+}
+
+/// Command that queries the state of the device.
+#[must_use]
+pub struct GetState;
+
+impl GetState {
+    /// Query the state of the device.
+    pub fn get_state(self, buffer: &'_ mut [u8]) -> (GetStateRecv, UsbReadControl<'_>) {
+        debug_assert!(!buffer.is_empty());
+        let control = UsbReadControl::new(REQUEST_TYPE, DFU_GETSTATE, 0, &mut buffer[..1]);
+        (GetStateRecv, control)
+    }
+}
+
+/// Read state after getting it from the device.
+#[must_use]
+pub struct GetStateRecv;
+
+impl GetStateRecv {
+    /// Read the state of the device.
+    pub fn chain(self, bytes: &[u8]) -> Result<State, Error> {
+        log::trace!("Received device state: {}", bytes.hex_dump());
+        let state = bytes
+            .first()
+            .copied()
+            .ok_or(Error::ResponseTooShort {
+                got: bytes.len(),
+                expected: 1,
+            })?
+            .into();
+        log::trace!("Device state: {:?}", state);
+
+        Ok(state)
+    }
+}
+
 /// Command that clears the status of the device.
 #[must_use]
 pub struct ClearStatus<T> {
@@ -107,6 +174,39 @@ impl<T> ChainedCommand for ClearStatus<T> {
     }
 }
 
+/// Command that aborts the current operation of the device.
+#[must_use]
+pub struct Abort<T> {
+    pub(crate) chained_command: T,
+}
+
+impl<T> ChainedCommand for Abort<T> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = (T, Option<UsbWriteControl<[u8; 0]>>);
+
+    /// Abort the current operation of the device.
+    fn chain(
+        self,
+        get_status::GetStatusMessage {
+            status: _,
+            poll_timeout: _,
+            state,
+            index: _,
+        }: Self::Arg,
+    ) -> (T, Option<UsbWriteControl<[u8; 0]>>) {
+        let next = self.chained_command;
+        if matches!(state, State::DfuDnloadIdle | State::DfuUploadIdle) {
+            log::trace!("Device is in {:?} state, aborting...", state);
+            let control = UsbWriteControl::new(REQUEST_TYPE, DFU_ABORT, 0, []);
+
+            (next, Some(control))
+        } else {
+            log::trace!("Device is in {:?} state, skip aborting", state);
+            (next, None)
+        }
+    }
+}
+
 /// Wait for the device to enter a specific state.
 #[must_use]
 pub struct WaitState<T> {
@@ -164,15 +264,13 @@ impl<T> ChainedCommand for WaitState<T> {
     type Arg = GetStatusMessage;
     type Into = Result<Self, Error>;
 
-    fn chain(
-        self,
-        GetStatusMessage {
-            status: _,
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        message.check_status()?;
+        let GetStatusMessage {
             poll_timeout,
             state,
-            index: _,
-        }: Self::Arg,
-    ) -> Self::Into {
+            ..
+        } = message;
         log::trace!("Device state: {:?}", state);
         if state == self.state || state == self.intermediate {
             Ok(WaitState {
@@ -0,0 +1,305 @@
+use bytes::Buf;
+use displaydoc::Display;
+use std::prelude::v1::*;
+use thiserror::Error;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Error while parsing an Intel HEX file.
+///
+/// The line is the line number of the offending record, starting at 1.
+#[derive(Debug, Display, Error)]
+pub enum Error {
+    /// missing start code at line {line}
+    MissingStartCode {
+        /// Line of the record.
+        line: usize,
+    },
+    /// invalid hexadecimal digits at line {line}
+    InvalidHex {
+        /// Line of the record.
+        line: usize,
+    },
+    /// invalid record length at line {line}
+    InvalidLength {
+        /// Line of the record.
+        line: usize,
+    },
+    /// invalid checksum at line {line}
+    InvalidChecksum {
+        /// Line of the record.
+        line: usize,
+    },
+    /// unsupported record type {record_type:#04x} at line {line}
+    UnsupportedRecordType {
+        /// Line of the record.
+        line: usize,
+        /// Type of the record.
+        record_type: u8,
+    },
+    /// invalid data for the record type at line {line}
+    InvalidRecord {
+        /// Line of the record.
+        line: usize,
+    },
+    /// address overflow at line {line}
+    AddressOverflow {
+        /// Line of the record.
+        line: usize,
+    },
+    /// data written twice at {address:#x}
+    OverlappingData {
+        /// Address of the overlapping data.
+        address: u32,
+    },
+    /// missing end of file record
+    MissingEndOfFile,
+}
+
+/// Contiguous data at an address of the device.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Segment {
+    /// Address of the first byte.
+    pub address: u32,
+    /// Data of the segment.
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    fn end(&self) -> u32 {
+        // Cannot overflow, checked while parsing.
+        self.address + self.data.len() as u32
+    }
+}
+
+/// Firmware read from an Intel HEX file.
+///
+/// The supported record types are data (`00`), end of file (`01`), extended segment address
+/// (`02`), extended linear address (`04`) and start linear address (`05`).
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct IntelHex {
+    segments: Vec<Segment>,
+    start_address: Option<u32>,
+}
+
+impl IntelHex {
+    /// Parse an Intel HEX file.
+    ///
+    /// The data records are gathered in segments of contiguous data, sorted by address. The
+    /// lines after the end of file record are ignored.
+    pub fn parse(src: &str) -> Result<Self, Error> {
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut start_address = None;
+        let mut base = 0u32;
+        let mut ended = false;
+
+        for (line, record) in src.lines().enumerate() {
+            let line = line + 1;
+            let record = record.trim();
+            if record.is_empty() {
+                continue;
+            }
+            let bytes = decode(
+                record
+                    .strip_prefix(':')
+                    .ok_or(Error::MissingStartCode { line })?,
+            )
+            .ok_or(Error::InvalidHex { line })?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(Error::InvalidLength { line });
+            }
+            if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+                return Err(Error::InvalidChecksum { line });
+            }
+
+            let mut bytes = &bytes[1..bytes.len() - 1];
+            let offset = bytes.get_u16();
+            let record_type = bytes.get_u8();
+            let mut data = bytes;
+            match (record_type, data.len()) {
+                (DATA, len) => {
+                    let address = base
+                        .checked_add(offset as u32)
+                        .filter(|address| address.checked_add(len as u32).is_some())
+                        .ok_or(Error::AddressOverflow { line })?;
+                    match segments.last_mut() {
+                        Some(segment) if segment.end() == address => {
+                            segment.data.extend_from_slice(data)
+                        }
+                        _ => segments.push(Segment {
+                            address,
+                            data: data.to_vec(),
+                        }),
+                    }
+                }
+                (END_OF_FILE, 0) => {
+                    ended = true;
+                    break;
+                }
+                (EXTENDED_SEGMENT_ADDRESS, 2) => base = (data.get_u16() as u32) << 4,
+                (EXTENDED_LINEAR_ADDRESS, 2) => base = (data.get_u16() as u32) << 16,
+                (START_LINEAR_ADDRESS, 4) => start_address = Some(data.get_u32()),
+                (
+                    END_OF_FILE
+                    | EXTENDED_SEGMENT_ADDRESS
+                    | EXTENDED_LINEAR_ADDRESS
+                    | START_LINEAR_ADDRESS,
+                    _,
+                ) => return Err(Error::InvalidRecord { line }),
+                (record_type, _) => return Err(Error::UnsupportedRecordType { line, record_type }),
+            }
+        }
+
+        if !ended {
+            return Err(Error::MissingEndOfFile);
+        }
+
+        segments.retain(|segment| !segment.data.is_empty());
+        segments.sort_by_key(|segment| segment.address);
+        let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+        for segment in segments {
+            match merged.last_mut() {
+                Some(last) if last.end() > segment.address => {
+                    return Err(Error::OverlappingData {
+                        address: segment.address,
+                    })
+                }
+                Some(last) if last.end() == segment.address => {
+                    last.data.extend_from_slice(&segment.data)
+                }
+                _ => merged.push(segment),
+            }
+        }
+
+        Ok(Self {
+            segments: merged,
+            start_address,
+        })
+    }
+
+    /// Segments of contiguous data, sorted by address.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Address to jump to when leaving DFU mode, from the start linear address record.
+    pub fn start_address(&self) -> Option<u32> {
+        self.start_address
+    }
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let hex = IntelHex::parse(
+            ":020000040800F2\n\
+             :0400000001020304F2\n\
+             :0400040005060708DE\n\
+             :0200100009F0F5\n\
+             :040000050800019955\n\
+             :00000001FF\n",
+        )
+        .unwrap();
+        assert_eq!(
+            hex.segments(),
+            &[
+                Segment {
+                    address: 0x08000000,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                },
+                Segment {
+                    address: 0x08000010,
+                    data: vec![0x09, 0xf0],
+                },
+            ]
+        );
+        assert_eq!(hex.start_address(), Some(0x08000199));
+    }
+
+    #[test]
+    fn parsing_unsorted_and_segment_address() {
+        let hex = IntelHex::parse(
+            "\r\n\
+             :020000021000EC\r\n\
+             :02000000AABB99\r\n\
+             :020000020000FC\r\n\
+             :02FFFE001122CE\r\n\
+             :00000001FF\r\n\
+             garbage after the end\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            hex.segments(),
+            &[Segment {
+                address: 0xfffe,
+                data: vec![0x11, 0x22, 0xaa, 0xbb],
+            }]
+        );
+        assert_eq!(hex.start_address(), None);
+    }
+
+    #[test]
+    fn parsing_errors() {
+        assert!(matches!(
+            IntelHex::parse("0000000001FF\n"),
+            Err(Error::MissingStartCode { line: 1 })
+        ));
+        assert!(matches!(
+            IntelHex::parse(":0000000G01FF\n"),
+            Err(Error::InvalidHex { line: 1 })
+        ));
+        assert!(matches!(
+            IntelHex::parse(":00000001F\n"),
+            Err(Error::InvalidHex { line: 1 })
+        ));
+        assert!(matches!(
+            IntelHex::parse("\n:01000000FF\n"),
+            Err(Error::InvalidLength { line: 2 })
+        ));
+        assert!(matches!(
+            IntelHex::parse(":00000001FE\n"),
+            Err(Error::InvalidChecksum { line: 1 })
+        ));
+        assert!(matches!(
+            IntelHex::parse(":0400000300000000F9\n"),
+            Err(Error::UnsupportedRecordType {
+                line: 1,
+                record_type: 0x03
+            })
+        ));
+        assert!(matches!(
+            IntelHex::parse(":0100000408F3\n"),
+            Err(Error::InvalidRecord { line: 1 })
+        ));
+        assert!(matches!(
+            IntelHex::parse(":02000004FFFFFC\n:02FFFF00AABB9B\n"),
+            Err(Error::AddressOverflow { line: 2 })
+        ));
+        assert!(matches!(
+            IntelHex::parse(":020000000102FB\n:0100010003FB\n:00000001FF\n"),
+            Err(Error::OverlappingData { address: 1 })
+        ));
+        assert!(matches!(
+            IntelHex::parse(":020000000102FB\n"),
+            Err(Error::MissingEndOfFile)
+        ));
+    }
+}
@@ -14,18 +14,32 @@ extern crate std;
 pub mod asynchronous;
 /// Commands to detach the device.
 pub mod detach;
+/// Commands specific to the DfuSe extensions.
+pub mod dfuse;
+/// DfuSe file format.
+pub mod dfuse_file;
 /// Commands to download a firmware into the device.
 pub mod download;
 /// Functional descriptor.
 pub mod functional_descriptor;
 /// Commands to get the status of the device.
 pub mod get_status;
+/// Intel HEX format.
+#[cfg(any(feature = "std", test))]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+pub mod intel_hex;
 /// Memory layout.
 pub mod memory_layout;
+/// Commands to bring the device back to the idle state.
+pub mod reach_idle;
+/// DFU file suffix.
+pub mod suffix;
 /// Generic synchronous implementation.
 #[cfg(any(feature = "std", test))]
 #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
 pub mod synchronous;
+/// Commands to upload a firmware from the device.
+pub mod upload;
 
 use core::convert::TryFrom;
 
@@ -57,8 +71,19 @@ pub enum Error {
     UnrecognizedStateCode(u8),
     /// Device response is too short (got: {got:?}, expected: {expected:?}).
     ResponseTooShort { got: usize, expected: usize },
-    /// Device status is in error: {0}
-    StatusError(Status),
+    /// Device status is in error: {status} (state: {state}, iString: {index}).
+    StatusError {
+        status: Status,
+        state: State,
+        index: u8,
+    },
+    /// Device status is in error: {status} (state: {state}): {description}
+    #[cfg(any(feature = "std", test))]
+    StatusErrorDescription {
+        status: Status,
+        state: State,
+        description: std::string::String,
+    },
     /// Device state is in error: {0}
     StateError(State),
     /// Unknown DFU protocol
@@ -70,6 +95,45 @@ pub enum Error {
     MemoryLayout(memory_layout::Error),
     /// Failed to parse dfuse address from interface string
     InvalidAddress,
+    /// The device does not support upload.
+    UploadNotSupported,
+    /// The memory range is outside of the device memory (address: {address:#x}, length: {length}).
+    AddressOutOfRange { address: u32, length: u32 },
+    /// Read-back verification failed at {address:#x}.
+    VerificationFailed { address: u32 },
+    /// The device cannot be verified after download as it is not manifestation tolerant.
+    VerificationNotSupported,
+    /// The device does not use the DfuSe extensions.
+    DfuseNotSupported,
+    /// The memory page at {address:#x} is not {attribute}.
+    MissingPageAttribute {
+        address: u32,
+        attribute: memory_layout::Attributes,
+    },
+    /// The segment at {address:#x} overlaps a previous segment or is not sorted by address.
+    OverlappingSegments { address: u32 },
+    /// The DfuSe file has no target for the alternate setting {alt_setting}.
+    MissingDfuseTarget { alt_setting: u8 },
+    /// Invalid DFU suffix: {0}
+    Suffix(suffix::Error),
+    /// Invalid DfuSe file: {0}
+    DfuseFile(dfuse_file::Error),
+}
+
+/// Outcome of a download with the generic synchronous and asynchronous implementations.
+#[cfg(any(feature = "std", test))]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[derive(Debug)]
+pub enum DownloadOutcome<T> {
+    /// The firmware has been downloaded and the device stayed on the bus (manifestation tolerant,
+    /// no USB reset occurred).
+    Done(T),
+    /// The firmware has been downloaded and a USB reset was performed, or the device left DFU
+    /// mode.
+    Reset,
+    /// The device was in runtime mode (`appIDLE`) and has been detached without downloading. It
+    /// must be opened again in DFU mode to download the firmware.
+    Detached,
 }
 
 /// Trait to implement lower level communication with a USB device.
@@ -111,6 +175,44 @@ pub trait DfuIo {
 
     /// Returns the functional descriptor of the device.
     fn functional_descriptor(&self) -> &functional_descriptor::FunctionalDescriptor;
+
+    /// Returns the alternate setting of the DFU interface, used to pick the target of a DfuSe
+    /// file.
+    ///
+    /// The default is 0.
+    fn alt_setting(&self) -> u8 {
+        0
+    }
+
+    /// Returns the vendor ID, product ID and release number of the device, used to check the
+    /// DFU suffix of a file.
+    ///
+    /// Returns `None` if it is unknown, which is the default. Only files whose suffix is for any
+    /// vendor and any product can then be downloaded without forcing it.
+    fn device_id(&self) -> Option<suffix::DeviceId> {
+        None
+    }
+
+    /// Returns whether this error means that the device is no longer on the bus.
+    ///
+    /// A device may reset before answering the last status request of the DfuSe Read Unprotect
+    /// command, or jump to its firmware before answering when leaving DFU mode. Only the errors
+    /// recognized here are tolerated then, the default recognizes none.
+    fn is_disconnected(&self, _error: &Self::Error) -> bool {
+        false
+    }
+
+    /// Returns the string descriptor at this index, used to describe status errors.
+    ///
+    /// Returns `None` if it is not supported, which is the default.
+    #[cfg(any(feature = "std", test))]
+    #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+    fn get_string_descriptor(
+        &self,
+        _index: u8,
+    ) -> Result<Option<std::string::String>, Self::Error> {
+        Ok(None)
+    }
 }
 
 /// The DFU protocol variant in use
@@ -119,9 +221,11 @@ pub enum DfuProtocol<M> {
     Dfu,
     /// STM DFU extensions aka DfuSe
     Dfuse {
-        /// Start memory address
+        /// Start memory address, the base address of the first region
         address: u32,
-        /// Memory layout for flash
+        /// Memory layout of all the regions, each page with its own address
+        ///
+        /// A layout whose first page is at 0 is relative to `address`.
         memory_layout: M,
     },
 }
@@ -133,19 +237,27 @@ impl DfuProtocol<memory_layout::MemoryLayout> {
         match version {
             (0x1, 0x10) => Ok(DfuProtocol::Dfu),
             (0x1, 0x1a) => {
-                let (rest, memory_layout) = interface_string
-                    .rsplit_once('/')
+                // "@Name/0xADDRESS/LAYOUT/0xADDRESS/LAYOUT..."
+                let (_name, segments) = interface_string
+                    .split_once('/')
                     .ok_or(Error::InvalidInterfaceString)?;
-                let memory_layout = memory_layout::MemoryLayout::try_from(memory_layout)
-                    .map_err(Error::MemoryLayout)?;
-                let (_rest, address) =
-                    rest.rsplit_once('/').ok_or(Error::InvalidInterfaceString)?;
-                let address = address
-                    .strip_prefix("0x")
-                    .and_then(|s| u32::from_str_radix(s, 16).ok())
-                    .ok_or(Error::InvalidAddress)?;
+                let mut segments = segments.split('/');
+                let mut start_address = None;
+                let mut memory_layout = memory_layout::MemoryLayout::new();
+                while let Some(address) = segments.next() {
+                    let layout = segments.next().ok_or(Error::InvalidInterfaceString)?;
+                    let address = address
+                        .trim()
+                        .strip_prefix("0x")
+                        .and_then(|s| u32::from_str_radix(s, 16).ok())
+                        .ok_or(Error::InvalidAddress)?;
+                    let region = memory_layout::MemoryLayout::parse(address, layout)
+                        .map_err(Error::MemoryLayout)?;
+                    start_address.get_or_insert(address);
+                    memory_layout.extend(region.iter());
+                }
                 Ok(DfuProtocol::Dfuse {
-                    address,
+                    address: start_address.ok_or(Error::InvalidInterfaceString)?,
                     memory_layout,
                 })
             }
@@ -158,6 +270,9 @@ impl DfuProtocol<memory_layout::MemoryLayout> {
 pub struct DfuSansIo {
     descriptor: FunctionalDescriptor,
     override_address: Option<u32>,
+    auto_detach: bool,
+    detach_timeout: Option<u16>,
+    mass_erase: bool,
 }
 
 impl DfuSansIo {
@@ -166,67 +281,318 @@ impl DfuSansIo {
         Self {
             descriptor,
             override_address: None,
+            auto_detach: false,
+            detach_timeout: None,
+            mass_erase: false,
         }
     }
 
     /// Create a state machine to download the firmware into the device.
+    ///
+    /// The device is brought back to `dfuIDLE` first if it was left in another DFU state. If the
+    /// device is in `appIDLE` and [`Self::set_auto_detach`] is enabled, it is detached instead.
+    ///
+    /// With DfuSe, the range must fit in the memory layout and only the pages overlapping it are
+    /// erased. These pages must be erasable (unless [`Self::set_mass_erase`] is enabled) and
+    /// writeable. More segments can be downloaded in the same session with
+    /// [`download::DownloadChunk::next_segment`].
     pub fn download<'a, Layout>(
         &'a self,
         protocol: &'a DfuProtocol<Layout>,
         length: u32,
-    ) -> Result<
-        get_status::GetStatus<get_status::ClearStatus<get_status::GetStatus<download::Start<'a>>>>,
-        Error,
-    >
+    ) -> Result<reach_idle::ReachIdle<download::Start<'a>>, Error>
+    where
+        Layout: AsRef<memory_layout::mem>,
+    {
+        self.download_at(protocol, None, length)
+    }
+
+    /// Same as [`Self::download`] but the DfuSe address can be given instead of the address set
+    /// by [`Self::set_address`].
+    pub(crate) fn download_at<'a, Layout>(
+        &'a self,
+        protocol: &'a DfuProtocol<Layout>,
+        address: Option<u32>,
+        length: u32,
+    ) -> Result<reach_idle::ReachIdle<download::Start<'a>>, Error>
     where
         Layout: AsRef<memory_layout::mem>,
     {
         let (protocol, end_pos) = match protocol {
             DfuProtocol::Dfu => (download::ProtocolData::Dfu, length),
             DfuProtocol::Dfuse {
-                address,
+                address: start_address,
                 memory_layout,
-                ..
             } => {
-                let address = self.override_address.unwrap_or(*address);
+                let address = self.dfuse_address(*start_address, address);
+                let layout = memory_layout.as_ref();
+                let offset = memory_layout::offset(layout, *start_address);
+                let memory_layout =
+                    download::check_range(layout, offset, address, length, self.mass_erase)?;
                 (
                     download::ProtocolData::Dfuse(download::DfuseProtocolData {
                         address,
-                        erased_pos: address,
+                        start_address: address,
+                        erased_pos: memory_layout[0].address.wrapping_add(offset),
+                        offset,
+                        mass_erase: self.mass_erase,
                         address_set: false,
-                        memory_layout: memory_layout.as_ref(),
+                        memory_layout,
+                        layout,
                     }),
                     address.checked_add(length).ok_or(Error::NoSpaceLeft)?,
                 )
             }
         };
 
-        Ok(get_status::GetStatus {
-            chained_command: get_status::ClearStatus {
-                chained_command: get_status::GetStatus {
-                    chained_command: download::Start {
-                        descriptor: &self.descriptor,
-                        protocol,
-                        end_pos,
-                    },
-                },
+        Ok(reach_idle::ReachIdle::new(download::Start {
+            descriptor: &self.descriptor,
+            protocol,
+            end_pos,
+            detach: self.auto_detach.then(|| self.detach_command()),
+        }))
+    }
+
+    /// Check that DfuSe segments, given as address and length, can be downloaded in one session.
+    ///
+    /// The segments must be sorted by address, must not overlap and must fit in the memory layout
+    /// with the attributes required by [`Self::download`]. Checking them all before the download
+    /// starts avoids erasing pages for a session that would fail on a later segment.
+    pub fn check_segments<Layout>(
+        &self,
+        protocol: &DfuProtocol<Layout>,
+        segments: impl IntoIterator<Item = (u32, u32)>,
+    ) -> Result<(), Error>
+    where
+        Layout: AsRef<memory_layout::mem>,
+    {
+        let DfuProtocol::Dfuse {
+            address: start_address,
+            memory_layout,
+        } = protocol
+        else {
+            return Err(Error::DfuseNotSupported);
+        };
+        let layout = memory_layout.as_ref();
+        let offset = memory_layout::offset(layout, *start_address);
+        let mut end_pos = None;
+        for (address, length) in segments {
+            if end_pos.is_some_and(|end_pos| address < end_pos) {
+                return Err(Error::OverlappingSegments { address });
+            }
+            download::check_range(layout, offset, address, length, self.mass_erase)?;
+            end_pos = Some(address.checked_add(length).ok_or(Error::NoSpaceLeft)?);
+        }
+        Ok(())
+    }
+
+    /// Create a state machine to upload the firmware from the device.
+    ///
+    /// At most `length` bytes are read. The upload ends earlier if the device sends a short frame.
+    /// The device is brought back to `dfuIDLE` first if it was left in another DFU state.
+    ///
+    /// With DfuSe, the memory is read from the start address of the protocol (or the address set
+    /// by [`Self::set_address`]), the range must fit in the memory layout and its pages must be
+    /// readable.
+    pub fn upload<'a, Layout>(
+        &'a self,
+        protocol: &'a DfuProtocol<Layout>,
+        length: u32,
+    ) -> Result<reach_idle::ReachIdle<upload::Start<'a>>, Error>
+    where
+        Layout: AsRef<memory_layout::mem>,
+    {
+        self.upload_at(protocol, None, length)
+    }
+
+    /// Same as [`Self::upload`] but the DfuSe address can be given instead of the address set by
+    /// [`Self::set_address`].
+    pub(crate) fn upload_at<'a, Layout>(
+        &'a self,
+        protocol: &'a DfuProtocol<Layout>,
+        address: Option<u32>,
+        length: u32,
+    ) -> Result<reach_idle::ReachIdle<upload::Start<'a>>, Error>
+    where
+        Layout: AsRef<memory_layout::mem>,
+    {
+        if !self.descriptor.can_upload {
+            return Err(Error::UploadNotSupported);
+        }
+
+        let protocol = match protocol {
+            DfuProtocol::Dfu => upload::ProtocolData::Dfu,
+            DfuProtocol::Dfuse {
+                address: start_address,
+                memory_layout,
+            } => {
+                let address = self.dfuse_address(*start_address, address);
+                let layout = memory_layout.as_ref();
+                let offset = memory_layout::offset(layout, *start_address);
+                let layout_address = address
+                    .checked_sub(offset)
+                    .filter(|&address| memory_layout::contains_range(layout, address, length))
+                    .ok_or(Error::AddressOutOfRange { address, length })?;
+                if let Some(page) = memory_layout::pages_in_range(layout, layout_address, length)
+                    .find(|page| !page.attributes.is_readable())
+                {
+                    return Err(Error::MissingPageAttribute {
+                        address: page.address.wrapping_add(offset),
+                        attribute: memory_layout::Attributes::READABLE,
+                    });
+                }
+                upload::ProtocolData::Dfuse {
+                    address,
+                    address_set: false,
+                }
+            }
+        };
+
+        Ok(reach_idle::ReachIdle::new(upload::Start {
+            descriptor: &self.descriptor,
+            end_pos: length,
+            protocol,
+        }))
+    }
+
+    /// DfuSe address to use, `address` if given, otherwise the address set by
+    /// [`Self::set_address`] or the start address of the protocol.
+    pub(crate) fn dfuse_address(&self, start_address: u32, address: Option<u32>) -> u32 {
+        address.or(self.override_address).unwrap_or(start_address)
+    }
+
+    /// Create a state machine to query the DfuSe commands supported by the device.
+    pub fn get_commands<'a, Layout>(
+        &'a self,
+        protocol: &DfuProtocol<Layout>,
+    ) -> Result<reach_idle::ReachIdle<dfuse::GetCommands<'a>>, Error> {
+        if !matches!(protocol, DfuProtocol::Dfuse { .. }) {
+            return Err(Error::DfuseNotSupported);
+        }
+
+        Ok(reach_idle::ReachIdle::new(dfuse::GetCommands {
+            descriptor: &self.descriptor,
+        }))
+    }
+
+    /// Create a state machine to remove the read protection of a DfuSe device.
+    ///
+    /// The device erases its whole memory and resets, it must be opened again afterwards.
+    pub fn read_unprotect<Layout>(
+        &self,
+        protocol: &DfuProtocol<Layout>,
+    ) -> Result<reach_idle::ReachIdle<dfuse::ReadUnprotect>, Error> {
+        if !matches!(protocol, DfuProtocol::Dfuse { .. }) {
+            return Err(Error::DfuseNotSupported);
+        }
+
+        Ok(reach_idle::ReachIdle::new(dfuse::ReadUnprotect))
+    }
+
+    /// Create a state machine to erase the whole memory of a DfuSe device.
+    pub fn mass_erase<Layout>(
+        &self,
+        protocol: &DfuProtocol<Layout>,
+    ) -> Result<reach_idle::ReachIdle<dfuse::MassErase>, Error> {
+        if !matches!(protocol, DfuProtocol::Dfuse { .. }) {
+            return Err(Error::DfuseNotSupported);
+        }
+
+        Ok(reach_idle::ReachIdle::new(dfuse::MassErase))
+    }
+
+    /// Create a state machine to leave DFU mode and jump to this address on a DfuSe device.
+    pub fn leave<Layout>(
+        &self,
+        protocol: &DfuProtocol<Layout>,
+        address: u32,
+    ) -> Result<reach_idle::ReachIdle<dfuse::Leave>, Error> {
+        if !matches!(protocol, DfuProtocol::Dfuse { .. }) {
+            return Err(Error::DfuseNotSupported);
+        }
+
+        Ok(reach_idle::ReachIdle::new(dfuse::Leave { address }))
+    }
+
+    /// Query the status of the device.
+    ///
+    /// Note that the device may change its state as a result of this request.
+    pub fn get_status(&self) -> get_status::GetStatus<get_status::ReturnStatus> {
+        get_status::GetStatus {
+            chained_command: get_status::ReturnStatus,
+        }
+    }
+
+    /// Query the state of the device.
+    ///
+    /// Unlike [`Self::get_status`], this request does not change the state of the device.
+    pub fn get_state(&self) -> get_status::GetState {
+        get_status::GetState
+    }
+
+    /// Create a state machine to abort the current operation of the device.
+    ///
+    /// A device in `dfuDNLOAD-IDLE` or `dfuUPLOAD-IDLE` goes back to `dfuIDLE`.
+    pub fn abort(&self) -> get_status::GetStatus<get_status::Abort<get_status::WaitState<()>>> {
+        get_status::GetStatus {
+            chained_command: get_status::Abort {
+                chained_command: get_status::WaitState::new(State::DfuIdle, State::DfuIdle, ()),
             },
-        })
+        }
+    }
+
+    /// Create a state machine to detach the device from runtime mode.
+    ///
+    /// The device must be in `appIDLE`. The timeout sent is the `wDetachTimeOut` of the device, or
+    /// the one set by [`Self::set_detach_timeout`] if it is shorter.
+    pub fn detach(&self) -> get_status::GetStatus<detach::Detach<detach::Detached>> {
+        get_status::GetStatus {
+            chained_command: self.detach_command(),
+        }
     }
 
-    /// Send a Detach request to the device
-    pub fn detach(&self) -> UsbWriteControl<[u8; 0]> {
-        const REQUEST_TYPE: u8 = 0b00100001;
-        const DFU_DETACH: u8 = 0;
-        UsbWriteControl::new(REQUEST_TYPE, DFU_DETACH, 1000, [])
+    fn detach_command(&self) -> detach::Detach<detach::Detached> {
+        let timeout = self
+            .detach_timeout
+            .map_or(self.descriptor.detach_timeout, |timeout| {
+                timeout.min(self.descriptor.detach_timeout)
+            });
+
+        detach::Detach {
+            descriptor: self.descriptor,
+            timeout,
+            // If the device won't detach itself, the host must reset it to enter DFU mode
+            chained_command: detach::Detached {
+                usb_reset: !self.descriptor.will_detach,
+            },
+        }
     }
 
-    /// Set the address onto which to download the firmware.
+    /// Set the address onto which to download the firmware or from which to upload it.
     ///
     /// This address is only used if the device uses the DfuSe protocol.
     pub fn set_address(&mut self, address: u32) {
         self.override_address = Some(address);
     }
+
+    /// Detach the device if it is in `appIDLE` when starting a download.
+    ///
+    /// The download then stops after the detach and the device must be opened again in DFU mode.
+    pub fn set_auto_detach(&mut self, auto_detach: bool) {
+        self.auto_detach = auto_detach;
+    }
+
+    /// Erase the whole memory at once instead of page by page when downloading with DfuSe.
+    pub fn set_mass_erase(&mut self, mass_erase: bool) {
+        self.mass_erase = mass_erase;
+    }
+
+    /// Set the timeout in milliseconds of the detach request.
+    ///
+    /// It is only used if it is shorter than the `wDetachTimeOut` of the device.
+    pub fn set_detach_timeout(&mut self, timeout: u16) {
+        self.detach_timeout = Some(timeout);
+    }
 }
 
 /// DFU Status.
@@ -477,4 +843,46 @@ mod tests {
     // ensure DfuIo can be made into an object
     const _: [&dyn DfuIo<Read = (), Write = (), Reset = (), MemoryLayout = (), Error = Error>; 0] =
         [];
+
+    #[test]
+    fn dfuse_interface_string_with_several_regions() {
+        let protocol = DfuProtocol::new(
+            "@Internal Flash  /0x08000000/04*016Kg,01*064Kg/0x08040000/07*128Kg",
+            (0x1, 0x1a),
+        )
+        .unwrap();
+        let DfuProtocol::Dfuse {
+            address,
+            memory_layout,
+        } = protocol
+        else {
+            panic!("not a DfuSe protocol");
+        };
+
+        assert_eq!(address, 0x08000000);
+        assert_eq!(memory_layout.len(), 12);
+        assert_eq!(memory_layout[4].address, 0x08010000);
+        assert_eq!(memory_layout[4].size, 0x10000);
+        assert_eq!(memory_layout[5].address, 0x08040000);
+        assert_eq!(memory_layout[11].address, 0x08040000 + 6 * 0x20000);
+
+        let pages = memory_layout::from_address(&memory_layout, 0x08040000).unwrap();
+        assert_eq!(pages.len(), 7);
+        let pages = memory_layout::from_address(&memory_layout, 0x08004010).unwrap();
+        assert_eq!(pages.len(), 11);
+        assert_eq!(pages[0].address, 0x08004000);
+        assert!(memory_layout::from_address(&memory_layout, 0x08020000).is_none());
+    }
+
+    #[test]
+    fn dfuse_interface_string_errors() {
+        assert!(matches!(
+            DfuProtocol::new("@Internal Flash  /0x08000000", (0x1, 0x1a)),
+            Err(Error::InvalidInterfaceString)
+        ));
+        assert!(matches!(
+            DfuProtocol::new("@Internal Flash  /08000000/04*016Kg", (0x1, 0x1a)),
+            Err(Error::InvalidAddress)
+        ));
+    }
 }
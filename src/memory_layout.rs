@@ -6,22 +6,233 @@ use std::prelude::v1::*;
 use thiserror::Error;
 
 /// Error while parsing a memory layout.
+///
+/// The position is the byte offset of the offending part in the parsed string.
 #[cfg(any(feature = "std", test))]
 #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
 #[derive(Debug, Display, Error)]
 pub enum Error {
-    /// invalid page format: {0}
-    InvalidPageFormat(String),
-    /// could not parse page count: {0}
-    ParseErrorPageCount(String),
-    /// could not parse page size: {0}
-    ParseErrorPageSize(String),
-    /// invalid prefix: {0}
-    InvalidPrefix(String),
+    /// invalid page format at {position}: {page}
+    InvalidPageFormat {
+        /// Position of the page.
+        position: usize,
+        /// Page that could not be parsed.
+        page: String,
+    },
+    /// could not parse page count at {position}: {count}
+    ParseErrorPageCount {
+        /// Position of the page count.
+        position: usize,
+        /// Page count that could not be parsed.
+        count: String,
+    },
+    /// could not parse page size at {position}: {size}
+    ParseErrorPageSize {
+        /// Position of the page size.
+        position: usize,
+        /// Page size that could not be parsed.
+        size: String,
+    },
+    /// invalid prefix at {position}: {prefix}
+    InvalidPrefix {
+        /// Position of the prefix.
+        position: usize,
+        /// Prefix that could not be parsed.
+        prefix: String,
+    },
+    /// memory layout exceeds the address space at {position}
+    AddressOverflow {
+        /// Position of the page.
+        position: usize,
+    },
+    /// invalid memory type at {position}: {memory_type}
+    InvalidMemoryType {
+        /// Position of the memory type.
+        position: usize,
+        /// Memory type that could not be parsed.
+        memory_type: char,
+    },
 }
 
-/// A memory page size.
-pub type MemoryPage = u32;
+/// Attributes of a memory page.
+///
+/// In the DfuSe memory layout, they are encoded as a letter from `a` to `g`: `a` is readable, `b`
+/// is erasable, `d` is writeable and the other letters are combinations of them (`g` being all
+/// three).
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Attributes(u8);
+
+impl Attributes {
+    /// The page can be read.
+    pub const READABLE: Self = Self(0b001);
+    /// The page can be erased.
+    pub const ERASABLE: Self = Self(0b010);
+    /// The page can be written.
+    pub const WRITEABLE: Self = Self(0b100);
+    /// The page can be read, erased and written.
+    pub const ALL: Self = Self(0b111);
+
+    /// Parse the attributes from a DfuSe memory type letter (`a` to `g`).
+    pub fn from_memory_type(memory_type: char) -> Option<Self> {
+        match memory_type {
+            'a'..='g' => Some(Self(memory_type as u8 - b'a' + 1)),
+            _ => None,
+        }
+    }
+
+    /// Returns the DfuSe memory type letter (`a` to `g`) of the attributes.
+    ///
+    /// Returns `None` if there is no attribute.
+    pub fn memory_type(self) -> Option<char> {
+        match self.0 {
+            0 => None,
+            bits => Some((b'a' + bits - 1) as char),
+        }
+    }
+
+    /// Returns `true` if all the attributes of `other` are set.
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the attributes that are set in `self` but missing from `other`, if any.
+    pub fn missing_from(self, other: Self) -> Option<Self> {
+        match self.0 & !other.0 {
+            0 => None,
+            bits => Some(Self(bits)),
+        }
+    }
+
+    /// Returns `true` if the page can be read.
+    pub fn is_readable(self) -> bool {
+        self.contains(Self::READABLE)
+    }
+
+    /// Returns `true` if the page can be erased.
+    pub fn is_erasable(self) -> bool {
+        self.contains(Self::ERASABLE)
+    }
+
+    /// Returns `true` if the page can be written.
+    pub fn is_writeable(self) -> bool {
+        self.contains(Self::WRITEABLE)
+    }
+}
+
+impl Default for Attributes {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl core::ops::BitOr for Attributes {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::fmt::Display for Attributes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut first = true;
+        for (attribute, name) in [
+            (Self::READABLE, "readable"),
+            (Self::ERASABLE, "erasable"),
+            (Self::WRITEABLE, "writeable"),
+        ] {
+            if self.contains(attribute) {
+                if !first {
+                    f.write_str(", ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        if first {
+            f.write_str("none")?;
+        }
+        Ok(())
+    }
+}
+
+/// A memory page.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MemoryPage {
+    /// Start address of the page.
+    pub address: u32,
+    /// Size of the page in bytes.
+    pub size: u32,
+    /// Attributes of the page.
+    pub attributes: Attributes,
+}
+
+impl MemoryPage {
+    /// Create a new readable, erasable and writeable [`MemoryPage`].
+    pub fn new(address: u32, size: u32) -> Self {
+        Self {
+            address,
+            size,
+            attributes: Attributes::ALL,
+        }
+    }
+
+    /// Returns `true` if the address is inside the page.
+    pub fn contains(&self, address: u32) -> bool {
+        address >= self.address && address - self.address < self.size
+    }
+}
+
+/// Returns the offset to add to the addresses of the pages to get the addresses on the device.
+///
+/// A memory layout whose first page is at 0, like one built with `MemoryLayout::try_from`, is
+/// relative to the start address of the protocol.
+pub(crate) fn offset(memory_layout: &mem, start_address: u32) -> u32 {
+    match memory_layout.first() {
+        Some(page) if page.address == 0 => start_address,
+        _ => 0,
+    }
+}
+
+/// Returns the pages of the memory layout from the page containing `address`.
+///
+/// Returns `None` if no page contains the address.
+pub(crate) fn from_address(memory_layout: &mem, address: u32) -> Option<&mem> {
+    memory_layout
+        .iter()
+        .position(|page| page.contains(address))
+        .map(|i| &memory_layout[i..])
+}
+
+/// Returns the pages of the memory layout overlapping the range `[address, address + length)`.
+pub(crate) fn pages_in_range(
+    memory_layout: &mem,
+    address: u32,
+    length: u32,
+) -> impl Iterator<Item = &MemoryPage> {
+    let start = address as u64;
+    let end = start + length as u64;
+    memory_layout.iter().filter(move |page| {
+        let page_start = page.address as u64;
+        page_start < end && page_start + page.size as u64 > start
+    })
+}
+
+/// Returns `true` if the range `[address, address + length)` is covered by the memory layout.
+pub(crate) fn contains_range(memory_layout: &mem, address: u32, length: u32) -> bool {
+    let end = address as u64 + length as u64;
+    let mut pos = address as u64;
+    while pos < end {
+        match memory_layout
+            .iter()
+            .find(|page| u32::try_from(pos).is_ok_and(|pos| page.contains(pos)))
+        {
+            Some(page) => pos = page.address as u64 + page.size as u64,
+            None => return false,
+        }
+    }
+    true
+}
 
 /// A slice of memory pages.
 #[allow(non_camel_case_types)]
@@ -45,6 +256,86 @@ impl MemoryLayout {
     pub fn new() -> Self {
         Self(Vec::new())
     }
+
+    /// Parse a DfuSe memory layout like `04*016Kg,01*064Kg,07*128Kg` starting at `address`.
+    ///
+    /// Each page is a count, a `*`, a size, an optional multiplier (`K`, `M`, `B` or a space)
+    /// and an optional memory type letter (`a` to `g`). Whitespace around the parts is ignored.
+    /// Pages without memory type are readable, erasable and writeable.
+    pub fn parse(address: u32, src: &str) -> Result<Self, Error> {
+        use core::str::FromStr;
+
+        let mut pages = Vec::new();
+        let mut address = Some(address);
+        // Byte offset of `s` in `src`.
+        let offset = |s: &str| s.as_ptr() as usize - src.as_ptr() as usize;
+
+        for s in src.split(',') {
+            let (count, rest) = s.split_once('*').ok_or_else(|| Error::InvalidPageFormat {
+                position: offset(s),
+                page: s.into(),
+            })?;
+            let count = count.trim();
+            let count = u32::from_str(count).map_err(|_| Error::ParseErrorPageCount {
+                position: offset(count),
+                count: count.into(),
+            })?;
+
+            let rest = rest.trim();
+            let (size, suffix) = rest.split_at(
+                rest.find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len()),
+            );
+            let size = u32::from_str(size).map_err(|_| Error::ParseErrorPageSize {
+                position: offset(size),
+                size: size.into(),
+            })?;
+
+            // The multiplier may be a space, which is then part of the whitespace.
+            let suffix = suffix.trim_start();
+            let (multiplier, memory_type) = match suffix.chars().next() {
+                Some('K') => (1024, suffix[1..].trim_start()),
+                Some('M') => (1024 * 1024, suffix[1..].trim_start()),
+                Some('B') => (1, suffix[1..].trim_start()),
+                _ => (1, suffix),
+            };
+            let mut chars = memory_type.chars();
+            let attributes = match (chars.next(), chars.next()) {
+                (None, _) => Attributes::ALL,
+                (Some(c), None) if c.is_ascii_lowercase() => Attributes::from_memory_type(c)
+                    .ok_or_else(|| Error::InvalidMemoryType {
+                        position: offset(memory_type),
+                        memory_type: c,
+                    })?,
+                _ => {
+                    return Err(Error::InvalidPrefix {
+                        position: offset(suffix),
+                        prefix: suffix.into(),
+                    })
+                }
+            };
+
+            let size = size
+                .checked_mul(multiplier)
+                .ok_or_else(|| Error::ParseErrorPageSize {
+                    position: offset(rest),
+                    size: rest.into(),
+                })?;
+            for _ in 0..count {
+                let page_address = address.ok_or(Error::AddressOverflow {
+                    position: offset(s),
+                })?;
+                pages.push(MemoryPage {
+                    address: page_address,
+                    size,
+                    attributes,
+                });
+                address = page_address.checked_add(size);
+            }
+        }
+
+        Ok(Self(pages))
+    }
 }
 
 #[cfg(any(feature = "std", test))]
@@ -81,38 +372,9 @@ impl core::ops::DerefMut for MemoryLayout {
 impl core::convert::TryFrom<&str> for MemoryLayout {
     type Error = Error;
 
+    /// Parse a DfuSe memory layout starting at address 0, see [`MemoryLayout::parse`].
     fn try_from(src: &str) -> Result<Self, Self::Error> {
-        use core::str::FromStr;
-
-        let mut pages = Vec::new();
-
-        for s in src.split(',') {
-            let (count, size) = s
-                .split_once('*')
-                .ok_or_else(|| Error::InvalidPageFormat(s.into()))?;
-            let (size, prefix) = size.split_at(
-                size.len()
-                    .checked_sub(2)
-                    .ok_or_else(|| Error::ParseErrorPageSize(size.into()))?,
-            );
-
-            let count =
-                u32::from_str(count).map_err(|_| Error::ParseErrorPageCount(count.into()))?;
-            let size = u32::from_str(size).map_err(|_| Error::ParseErrorPageSize(size.into()))?;
-            let prefix = match &prefix[..1] {
-                "K" => 1024,
-                "M" => 1024 * 1024,
-                " " => 1,
-                other => return Err(Error::InvalidPrefix(other.into())),
-            };
-
-            let size = size * prefix;
-            for _ in 0..count {
-                pages.push(size);
-            }
-        }
-
-        Ok(Self(pages))
+        Self::parse(0, src)
     }
 }
 
@@ -121,12 +383,16 @@ mod tests {
     use super::*;
     use core::convert::TryFrom;
 
+    fn sizes(m: &MemoryLayout) -> Vec<u32> {
+        m.iter().map(|page| page.size).collect()
+    }
+
     #[test]
     fn parsing() {
         let s = "04*032Kg,01*128Kg,07*256Kg";
         let m = MemoryLayout::try_from(s).unwrap();
         assert_eq!(
-            m.as_slice(),
+            sizes(&m),
             &[
                 32768, 32768, 32768, 32768, 131072, 262144, 262144, 262144, 262144, 262144, 262144,
                 262144
@@ -138,6 +404,95 @@ mod tests {
     fn parsing_stm32_defuse_extensions() {
         let s = "4*32Kg,1*128Kg";
         let m = MemoryLayout::try_from(s).unwrap();
-        assert_eq!(m.as_slice(), &[32768, 32768, 32768, 32768, 131072]);
+        assert_eq!(sizes(&m), &[32768, 32768, 32768, 32768, 131072]);
+    }
+
+    #[test]
+    fn parsing_attributes() {
+        let s = "01*016Ka,01*016Kb,01*016Kc,01*016Kd,01*016Ke,01*016Kf,01*016Kg";
+        let m = MemoryLayout::try_from(s).unwrap();
+        let attributes: Vec<_> = m.iter().map(|page| page.attributes).collect();
+        assert_eq!(
+            attributes,
+            &[
+                Attributes::READABLE,
+                Attributes::ERASABLE,
+                Attributes::READABLE | Attributes::ERASABLE,
+                Attributes::WRITEABLE,
+                Attributes::READABLE | Attributes::WRITEABLE,
+                Attributes::ERASABLE | Attributes::WRITEABLE,
+                Attributes::ALL,
+            ]
+        );
+        for (page, memory_type) in m.iter().zip('a'..='g') {
+            assert_eq!(page.attributes.memory_type(), Some(memory_type));
+        }
+
+        let required = Attributes::ERASABLE | Attributes::WRITEABLE;
+        assert_eq!(required.missing_from(m[0].attributes), Some(required));
+        assert_eq!(
+            required.missing_from(m[2].attributes),
+            Some(Attributes::WRITEABLE)
+        );
+        assert_eq!(required.missing_from(m[5].attributes), None);
+    }
+
+    #[test]
+    fn parsing_whitespace_and_suffixes() {
+        let s = " 2*4 g, 1 * 1Ke ,1*512B,1*2M,1*8";
+        let m = MemoryLayout::try_from(s).unwrap();
+        assert_eq!(sizes(&m), &[4, 4, 1024, 512, 2 * 1024 * 1024, 8]);
+        assert_eq!(
+            m[2].attributes,
+            Attributes::READABLE | Attributes::WRITEABLE
+        );
+        assert_eq!(m[3].attributes, Attributes::ALL);
+        assert_eq!(m[5].attributes, Attributes::ALL);
+    }
+
+    #[test]
+    fn parsing_with_address() {
+        let m = MemoryLayout::parse(0x08000000, "2*16Kg,1*64Kg").unwrap();
+        let addresses: Vec<_> = m.iter().map(|page| page.address).collect();
+        assert_eq!(addresses, &[0x08000000, 0x08004000, 0x08008000]);
+        assert!(contains_range(&m, 0x08004000, 0x10000));
+        assert!(!contains_range(&m, 0x08004000, 0x14001));
+        assert!(!contains_range(&m, 0x07ffffff, 2));
+
+        assert!(matches!(
+            MemoryLayout::parse(0xffff0000, "1*32Kg,2*32Kg"),
+            Err(Error::AddressOverflow { position: 7 })
+        ));
+    }
+
+    #[test]
+    fn parsing_errors() {
+        assert!(matches!(
+            MemoryLayout::try_from("4*32Kg,1x128Kg"),
+            Err(Error::InvalidPageFormat { position: 7, .. })
+        ));
+        assert!(matches!(
+            MemoryLayout::try_from("4*32Kg, x*128Kg"),
+            Err(Error::ParseErrorPageCount { position: 8, .. })
+        ));
+        assert!(matches!(
+            MemoryLayout::try_from("4*Kg"),
+            Err(Error::ParseErrorPageSize { position: 2, .. })
+        ));
+        assert!(matches!(
+            MemoryLayout::try_from("4*32Kg,1*128Gg"),
+            Err(Error::InvalidPrefix { position: 12, .. })
+        ));
+        assert!(matches!(
+            MemoryLayout::try_from("4*32Kh"),
+            Err(Error::InvalidMemoryType {
+                position: 5,
+                memory_type: 'h'
+            })
+        ));
+        assert!(matches!(
+            MemoryLayout::try_from("1*8192M"),
+            Err(Error::ParseErrorPageSize { position: 2, .. })
+        ));
     }
 }
@@ -0,0 +1,109 @@
+use super::*;
+use get_status::{DFU_ABORT, DFU_CLRSTATUS};
+
+const REQUEST_TYPE: u8 = 0b00100001;
+
+/// Bring the device back to `dfuIDLE` before chaining into another command.
+///
+/// The status of the device is queried until it reaches `dfuIDLE`, clearing errors and aborting
+/// pending operations as needed. States from which the device cannot be brought back to
+/// `dfuIDLE` (`appIDLE`, `dfuMANIFEST-WAIT-RESET`, ...) are passed on to the chained command, as
+/// well as a state that did not change after clearing the status or aborting.
+#[must_use]
+pub struct ReachIdle<T> {
+    chained_command: T,
+    status: Option<get_status::GetStatusMessage>,
+    poll_timeout: u64,
+    /// State in which the status was cleared or the operation aborted.
+    recovering: Option<State>,
+}
+
+/// A step when reaching the idle state.
+#[allow(missing_docs)]
+pub enum Step<T: ChainedCommand> {
+    Break(T::Into),
+    /// The state has not been reached and the status of the device must be queried.
+    Wait(get_status::GetStatus<ReachIdle<T>>, u64),
+}
+
+impl<T> ReachIdle<T>
+where
+    T: ChainedCommand<Arg = get_status::GetStatusMessage>,
+{
+    /// Create a new instance of [`ReachIdle`].
+    pub fn new(chained_command: T) -> Self {
+        Self {
+            chained_command,
+            status: None,
+            poll_timeout: 0,
+            recovering: None,
+        }
+    }
+
+    /// Returns the next command after reaching the idle state.
+    pub fn next(self) -> Step<T> {
+        match self.status {
+            Some(status) => {
+                log::trace!("Device state reached: {:?}", status.state);
+                Step::Break(self.chained_command.chain(status))
+            }
+            None => {
+                let poll_timeout = self.poll_timeout;
+                log::trace!(
+                    "Waiting for device to be idle (poll timeout: {})",
+                    poll_timeout
+                );
+
+                Step::Wait(
+                    get_status::GetStatus {
+                        chained_command: self,
+                    },
+                    poll_timeout,
+                )
+            }
+        }
+    }
+}
+
+impl<T> ChainedCommand for ReachIdle<T> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = (Self, Option<UsbWriteControl<[u8; 0]>>);
+
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        let state = message.state;
+        log::trace!("Device state: {:?}", state);
+        let (status, poll_timeout, request) = match state {
+            _ if self.recovering == Some(state) => {
+                log::trace!("Device is still in {:?} state, giving up", state);
+                (Some(message), 0, None)
+            }
+            State::DfuError => {
+                log::trace!("Device is in error state, clearing status...");
+                (None, 0, Some(DFU_CLRSTATUS))
+            }
+            State::DfuDnloadIdle | State::DfuUploadIdle => {
+                log::trace!("Device is in {:?} state, aborting...", state);
+                (None, 0, Some(DFU_ABORT))
+            }
+            State::DfuDnloadSync
+            | State::DfuDnbusy
+            | State::DfuManifestSync
+            | State::DfuManifest => (None, message.poll_timeout, None),
+            State::DfuIdle
+            | State::AppIdle
+            | State::AppDetach
+            | State::DfuManifestWaitReset
+            | State::Other(_) => (Some(message), 0, None),
+        };
+
+        let next = ReachIdle {
+            chained_command: self.chained_command,
+            status,
+            poll_timeout,
+            recovering: request.map(|_| state),
+        };
+        let control = request.map(|request| UsbWriteControl::new(REQUEST_TYPE, request, 0, []));
+
+        (next, control)
+    }
+}
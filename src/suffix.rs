@@ -0,0 +1,464 @@
+use bytes::{Buf, BufMut};
+use displaydoc::Display;
+#[cfg(any(feature = "std", test))]
+use thiserror::Error;
+
+/// Length of the DFU suffix.
+pub const SUFFIX_LENGTH: usize = 16;
+/// Signature of the DFU suffix, as it is stored in the file.
+pub const SIGNATURE: &[u8; 3] = b"UFD";
+/// bcdDFU of DFU 1.0 and 1.1 files.
+pub const DFU_VERSION: u16 = 0x0100;
+/// bcdDFU of DfuSe files.
+pub const DFUSE_VERSION: u16 = 0x011a;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Compute the CRC of a DFU file.
+///
+/// This is the CRC-32 used by Ethernet and zip but without the final inversion, as defined by
+/// the DFU specification.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0xffffffff, bytes)
+}
+
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Error when reading the DFU suffix.
+#[derive(Debug, Display)]
+#[cfg_attr(any(feature = "std", test), derive(Error))]
+#[allow(missing_docs)]
+pub enum Error {
+    /// The file is too short to have a DFU suffix (got: {got}, expected: {expected}).
+    DataTooShort { got: usize, expected: usize },
+    /// The DFU suffix signature is invalid.
+    InvalidSignature,
+    /// The DFU suffix length is invalid: {0}.
+    InvalidLength(u8),
+    /// The CRC of the file does not match its DFU suffix (got: {got:#010x}, expected: {expected:#010x}).
+    CrcMismatch { got: u32, expected: u32 },
+    /// The file is for the vendor ID {file:#06x} but the device has {device:#06x}.
+    VendorMismatch { file: u16, device: u16 },
+    /// The file is for the product ID {file:#06x} but the device has {device:#06x}.
+    ProductMismatch { file: u16, device: u16 },
+    /// The file is for the device release {file:#06x} but the device has {device:#06x}.
+    DeviceMismatch { file: u16, device: u16 },
+    /// The file is for the vendor ID {vendor:#06x} and product ID {product:#06x} but the identity of the device is unknown.
+    UnknownDevice { vendor: u16, product: u16 },
+}
+
+/// Identity of a USB device, compared with the DFU suffix of a file.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DeviceId {
+    /// idVendor.
+    pub vendor: u16,
+    /// idProduct.
+    pub product: u16,
+    /// bcdDevice.
+    pub device: u16,
+}
+
+/// DFU suffix at the end of a DFU file.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Suffix {
+    /// bcdDevice.
+    ///
+    /// Release number of the device associated with this file, or `0xffff` for any.
+    pub device: u16,
+    /// idProduct.
+    ///
+    /// Product ID associated with this file, or `0xffff` for any.
+    pub product: u16,
+    /// idVendor.
+    ///
+    /// Vendor ID associated with this file, or `0xffff` for any.
+    pub vendor: u16,
+    /// bcdDFU.
+    ///
+    /// DFU specification number, `0x0100` or `0x011a` for DfuSe.
+    pub dfu_version: u16,
+    /// bLength.
+    ///
+    /// Length of the suffix, at least 16 bytes.
+    pub length: u8,
+    /// dwCRC.
+    ///
+    /// CRC of the whole file except this field.
+    pub crc: u32,
+}
+
+impl Suffix {
+    /// Read the DFU suffix at the end of a file.
+    ///
+    /// The CRC is not checked, see [`split`].
+    pub fn from_bytes(file: &[u8]) -> Result<Self, Error> {
+        let mut bytes = file
+            .len()
+            .checked_sub(SUFFIX_LENGTH)
+            .map(|start| &file[start..])
+            .ok_or(Error::DataTooShort {
+                got: file.len(),
+                expected: SUFFIX_LENGTH,
+            })?;
+
+        let device = bytes.get_u16_le();
+        let product = bytes.get_u16_le();
+        let vendor = bytes.get_u16_le();
+        let dfu_version = bytes.get_u16_le();
+        if &bytes[..SIGNATURE.len()] != SIGNATURE {
+            return Err(Error::InvalidSignature);
+        }
+        bytes.advance(SIGNATURE.len());
+        let length = bytes.get_u8();
+        if (length as usize) < SUFFIX_LENGTH {
+            return Err(Error::InvalidLength(length));
+        }
+        if length as usize > file.len() {
+            return Err(Error::DataTooShort {
+                got: file.len(),
+                expected: length as usize,
+            });
+        }
+        let crc = bytes.get_u32_le();
+
+        Ok(Self {
+            device,
+            product,
+            vendor,
+            dfu_version,
+            length,
+            crc,
+        })
+    }
+
+    /// Check that the file is meant for this device.
+    ///
+    /// A field of the suffix set to `0xffff` matches any device. If the identity of the device is
+    /// unknown, only a file for any vendor and any product is accepted.
+    pub fn check_device(&self, id: Option<&DeviceId>) -> Result<(), Error> {
+        let id = match id {
+            Some(id) => id,
+            None if self.vendor == 0xffff && self.product == 0xffff => return Ok(()),
+            None => {
+                return Err(Error::UnknownDevice {
+                    vendor: self.vendor,
+                    product: self.product,
+                })
+            }
+        };
+        let matches = |file: u16, device: u16| file == 0xffff || file == device;
+        if !matches(self.vendor, id.vendor) {
+            return Err(Error::VendorMismatch {
+                file: self.vendor,
+                device: id.vendor,
+            });
+        }
+        if !matches(self.product, id.product) {
+            return Err(Error::ProductMismatch {
+                file: self.product,
+                device: id.product,
+            });
+        }
+        if !matches(self.device, id.device) {
+            return Err(Error::DeviceMismatch {
+                file: self.device,
+                device: id.device,
+            });
+        }
+        Ok(())
+    }
+
+    /// Write the DFU suffix.
+    ///
+    /// The suffix is always written with its 16 standard bytes.
+    pub fn to_bytes(&self) -> [u8; SUFFIX_LENGTH] {
+        let mut bytes = [0; SUFFIX_LENGTH];
+        let mut buf = &mut bytes[..];
+        buf.put_u16_le(self.device);
+        buf.put_u16_le(self.product);
+        buf.put_u16_le(self.vendor);
+        buf.put_u16_le(self.dfu_version);
+        buf.put_slice(SIGNATURE);
+        buf.put_u8(self.length);
+        buf.put_u32_le(self.crc);
+        bytes
+    }
+}
+
+/// Builder of the DFU suffix of a firmware.
+///
+/// The vendor ID, product ID and device release number default to `0xffff` (any device) and
+/// bcdDFU defaults to [`DFU_VERSION`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Builder {
+    device: u16,
+    product: u16,
+    vendor: u16,
+    dfu_version: u16,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            device: 0xffff,
+            product: 0xffff,
+            vendor: 0xffff,
+            dfu_version: DFU_VERSION,
+        }
+    }
+}
+
+impl Builder {
+    /// Create a new instance of [`Builder`] matching any device.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the vendor ID (idVendor).
+    pub fn vendor(&mut self, vendor: u16) -> &mut Self {
+        self.vendor = vendor;
+        self
+    }
+
+    /// Set the product ID (idProduct).
+    pub fn product(&mut self, product: u16) -> &mut Self {
+        self.product = product;
+        self
+    }
+
+    /// Set the release number of the device (bcdDevice).
+    pub fn device(&mut self, device: u16) -> &mut Self {
+        self.device = device;
+        self
+    }
+
+    /// Set the DFU specification number (bcdDFU), [`DFU_VERSION`] or [`DFUSE_VERSION`].
+    pub fn dfu_version(&mut self, dfu_version: u16) -> &mut Self {
+        self.dfu_version = dfu_version;
+        self
+    }
+
+    /// Build the DFU suffix of a firmware, computing its CRC.
+    pub fn build(&self, firmware: &[u8]) -> Suffix {
+        let mut suffix = Suffix {
+            device: self.device,
+            product: self.product,
+            vendor: self.vendor,
+            dfu_version: self.dfu_version,
+            length: SUFFIX_LENGTH as u8,
+            crc: 0,
+        };
+        let bytes = suffix.to_bytes();
+        suffix.crc = crc32_update(crc32(firmware), &bytes[..SUFFIX_LENGTH - 4]);
+        suffix
+    }
+
+    /// Append the DFU suffix to a firmware.
+    #[cfg(any(feature = "std", test))]
+    #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+    pub fn append(&self, firmware: &mut std::vec::Vec<u8>) -> Suffix {
+        let suffix = self.build(firmware);
+        firmware.extend_from_slice(&suffix.to_bytes());
+        suffix
+    }
+}
+
+/// Check the DFU suffix of a file and split the file into the firmware and the suffix.
+pub fn split(file: &[u8]) -> Result<(&[u8], Suffix), Error> {
+    let suffix = Suffix::from_bytes(file)?;
+    let crc = crc32(&file[..file.len() - 4]);
+    if crc != suffix.crc {
+        return Err(Error::CrcMismatch {
+            got: crc,
+            expected: suffix.crc,
+        });
+    }
+    log::trace!("DFU suffix: {:?}", suffix);
+
+    Ok((&file[..file.len() - suffix.length as usize], suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::prelude::v1::*;
+
+    fn with_suffix(firmware: &[u8]) -> Vec<u8> {
+        let mut file = firmware.to_vec();
+        file.extend_from_slice(&[0x00, 0x02, 0x11, 0xdf, 0x83, 0x04, 0x1a, 0x01]);
+        file.extend_from_slice(b"UFD\x10");
+        let crc = crc32(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), !0xcbf43926);
+        assert_eq!(crc32(b""), 0xffffffff);
+    }
+
+    #[test]
+    fn splitting() {
+        let file = with_suffix(&[1, 2, 3, 4]);
+        let (firmware, suffix) = split(&file).unwrap();
+        assert_eq!(firmware, &[1, 2, 3, 4]);
+        assert_eq!(
+            suffix,
+            Suffix {
+                device: 0x0200,
+                product: 0xdf11,
+                vendor: 0x0483,
+                dfu_version: 0x011a,
+                length: 16,
+                crc: crc32(&file[..file.len() - 4]),
+            }
+        );
+    }
+
+    #[test]
+    fn building() {
+        let mut file = vec![1, 2, 3, 4];
+        let suffix = Builder::new()
+            .vendor(0x0483)
+            .product(0xdf11)
+            .device(0x0200)
+            .dfu_version(DFUSE_VERSION)
+            .append(&mut file);
+        assert_eq!(file, with_suffix(&[1, 2, 3, 4]));
+        assert_eq!(split(&file).unwrap(), (&[1, 2, 3, 4][..], suffix));
+
+        let mut file = vec![];
+        let suffix = Builder::new().append(&mut file);
+        assert_eq!(
+            &file[..8],
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x01]
+        );
+        assert_eq!(split(&file).unwrap(), (&[][..], suffix));
+    }
+
+    #[test]
+    fn checking_device() {
+        let file = with_suffix(&[]);
+        let (_, suffix) = split(&file).unwrap();
+        let id = DeviceId {
+            vendor: 0x0483,
+            product: 0xdf11,
+            device: 0x0200,
+        };
+        assert!(suffix.check_device(Some(&id)).is_ok());
+        assert!(matches!(
+            suffix.check_device(Some(&DeviceId {
+                vendor: 0x1209,
+                ..id
+            })),
+            Err(Error::VendorMismatch {
+                file: 0x0483,
+                device: 0x1209
+            })
+        ));
+        assert!(matches!(
+            suffix.check_device(Some(&DeviceId {
+                product: 0xdf12,
+                ..id
+            })),
+            Err(Error::ProductMismatch { .. })
+        ));
+        assert!(matches!(
+            suffix.check_device(Some(&DeviceId {
+                device: 0x2200,
+                ..id
+            })),
+            Err(Error::DeviceMismatch { .. })
+        ));
+
+        let any = Suffix {
+            vendor: 0xffff,
+            product: 0xffff,
+            device: 0xffff,
+            ..suffix
+        };
+        assert!(any
+            .check_device(Some(&DeviceId {
+                vendor: 0x1209,
+                product: 0x0001,
+                device: 0x0100,
+            }))
+            .is_ok());
+        assert!(any.check_device(None).is_ok());
+        assert!(matches!(
+            suffix.check_device(None),
+            Err(Error::UnknownDevice {
+                vendor: 0x0483,
+                product: 0xdf11
+            })
+        ));
+        let any_product = Suffix {
+            product: 0xffff,
+            ..suffix
+        };
+        assert!(matches!(
+            any_product.check_device(None),
+            Err(Error::UnknownDevice { .. })
+        ));
+    }
+
+    #[test]
+    fn splitting_errors() {
+        let file = with_suffix(&[1, 2, 3, 4]);
+        assert!(matches!(split(&file[4..]), Err(Error::CrcMismatch { .. })));
+        assert!(matches!(
+            split(&file[..15]),
+            Err(Error::DataTooShort {
+                got: 15,
+                expected: 16
+            })
+        ));
+        assert!(matches!(
+            split(&[1, 2, 3, 4]),
+            Err(Error::DataTooShort { .. })
+        ));
+
+        let mut invalid = file.clone();
+        invalid[12] = b'X';
+        assert!(matches!(split(&invalid), Err(Error::InvalidSignature)));
+
+        let mut invalid = file.clone();
+        invalid[15] = 8;
+        assert!(matches!(split(&invalid), Err(Error::InvalidLength(8))));
+
+        let mut invalid = file.clone();
+        invalid[15] = 32;
+        assert!(matches!(
+            split(&invalid),
+            Err(Error::DataTooShort {
+                got: 20,
+                expected: 32
+            })
+        ));
+    }
+}
@@ -3,6 +3,44 @@ use std::convert::TryFrom;
 use std::io::Cursor;
 use std::prelude::v1::*;
 
+macro_rules! wait_status {
+    ($io:expr, $buffer:expr, $cmd:expr) => {{
+        let mut cmd = $cmd;
+        loop {
+            cmd = match cmd.next() {
+                get_status::Step::Break(cmd) => break cmd,
+                get_status::Step::Wait(cmd, poll_timeout) => {
+                    std::thread::sleep(std::time::Duration::from_millis(poll_timeout));
+                    let (cmd, mut control) = cmd.get_status(&mut $buffer[..]);
+                    let n = control.execute($io)?;
+                    describe_error($io, cmd.chain(&$buffer[..n as usize])?)?
+                }
+            };
+        }
+    }};
+}
+
+macro_rules! reach_idle {
+    ($io:expr, $buffer:expr, $cmd:expr) => {{
+        let mut cmd = $cmd;
+        loop {
+            cmd = match cmd.next() {
+                reach_idle::Step::Break(cmd) => break cmd,
+                reach_idle::Step::Wait(cmd, poll_timeout) => {
+                    std::thread::sleep(std::time::Duration::from_millis(poll_timeout));
+                    let (cmd, mut control) = cmd.get_status(&mut $buffer[..]);
+                    let n = control.execute($io)?;
+                    let (cmd, control) = cmd.chain(&$buffer[..n as usize])?;
+                    if let Some(control) = control {
+                        control.execute($io)?;
+                    }
+                    cmd
+                }
+            };
+        }
+    }};
+}
+
 struct Buffer<R: std::io::Read> {
     reader: R,
     buf: Box<[u8]>,
@@ -51,6 +89,9 @@ where
     io: IO,
     dfu: DfuSansIo,
     buffer: Vec<u8>,
+    verify: bool,
+    leave: bool,
+    force: bool,
     progress: Option<Box<dyn FnMut(usize)>>,
 }
 
@@ -68,11 +109,14 @@ where
             io,
             dfu: DfuSansIo::new(descriptor),
             buffer: vec![0x00; transfer_size],
+            verify: false,
+            leave: false,
+            force: false,
             progress: None,
         }
     }
 
-    /// Override the address onto which the firmware is downloaded.
+    /// Override the address onto which the firmware is downloaded or from which it is uploaded.
     ///
     /// This address is only used if the device uses the DfuSe protocol.
     pub fn override_address(&mut self, address: u32) -> &mut Self {
@@ -80,6 +124,51 @@ where
         self
     }
 
+    /// Read back the firmware after download and compare it with the source.
+    ///
+    /// With DfuSe, the verification happens before leaving DFU mode. Otherwise the device must be
+    /// manifestation tolerant.
+    pub fn with_verify(&mut self, verify: bool) -> &mut Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Detach the device if it is in runtime mode (`appIDLE`) when starting a download.
+    ///
+    /// The download then returns [`DownloadOutcome::Detached`] once the device has been detached
+    /// and reset if needed. The caller must open the device again in DFU mode to continue.
+    pub fn with_auto_detach(&mut self, auto_detach: bool) -> &mut Self {
+        self.dfu.set_auto_detach(auto_detach);
+        self
+    }
+
+    /// Leave DFU mode after downloading with DfuSe, jumping to the start address.
+    ///
+    /// The device then runs the firmware without a USB reset. As with [`Self::leave`], an error
+    /// on the last status request is returned unless [`DfuIo::is_disconnected`] recognizes it.
+    pub fn with_leave(&mut self, leave: bool) -> &mut Self {
+        self.leave = leave;
+        self
+    }
+
+    /// Download DFU files even if their suffix does not match the identity of the device.
+    pub fn with_force(&mut self, force: bool) -> &mut Self {
+        self.force = force;
+        self
+    }
+
+    /// Erase the whole memory at once instead of page by page when downloading with DfuSe.
+    pub fn with_mass_erase(&mut self, mass_erase: bool) -> &mut Self {
+        self.dfu.set_mass_erase(mass_erase);
+        self
+    }
+
+    /// Use a detach timeout in milliseconds shorter than the `wDetachTimeOut` of the device.
+    pub fn with_detach_timeout(&mut self, timeout: u16) -> &mut Self {
+        self.dfu.set_detach_timeout(timeout);
+        self
+    }
+
     /// Use this closure to show progress.
     pub fn with_progress(&mut self, progress: impl FnMut(usize) + 'static) -> &mut Self {
         self.progress = Some(Box::new(progress));
@@ -99,9 +188,8 @@ where
 {
     /// Download a firmware into the device from a slice.
     ///
-    /// Returns `Some(Self)` if the device stayed on the bus (manifestation tolerant, no USB reset
-    /// occurred) or `None` if a USB reset was performed.
-    pub fn download_from_slice(self, slice: &[u8]) -> Result<Option<Self>, IO::Error> {
+    /// Returns whether the device stayed on the bus, was reset or was detached.
+    pub fn download_from_slice(self, slice: &[u8]) -> Result<DownloadOutcome<Self>, IO::Error> {
         let length = slice.len();
         let cursor = Cursor::new(slice);
         self.download(
@@ -112,75 +200,234 @@ where
 
     /// Download a firmware into the device from a reader.
     ///
-    /// Returns `Some(Self)` if the device stayed on the bus (manifestation tolerant, no USB reset
-    /// occurred) or `None` if a USB reset was performed.
+    /// Returns whether the device stayed on the bus, was reset or was detached.
     pub fn download<R: std::io::Read>(
+        self,
+        reader: R,
+        length: u32,
+    ) -> Result<DownloadOutcome<Self>, IO::Error> {
+        self.download_segments(None, reader, length, std::iter::empty(), None)
+    }
+
+    /// Download a DFU file into the device.
+    ///
+    /// The DFU suffix at the end of the file is checked and stripped before the download. The
+    /// download is refused if the suffix does not match the identity of the device, or names a
+    /// vendor or a product while [`DfuIo::device_id`] does not give this identity, unless
+    /// [`Self::with_force`] is used. If the rest of the file is a DfuSe file, it is downloaded
+    /// with [`Self::download_dfuse_file`].
+    /// Returns whether the device stayed on the bus, was reset or was detached.
+    pub fn download_file(self, file: &[u8]) -> Result<DownloadOutcome<Self>, IO::Error> {
+        let (firmware, suffix) = suffix::split(file).map_err(Error::Suffix)?;
+        match suffix.check_device(self.io.device_id().as_ref()) {
+            Err(err) if self.force => log::warn!("Forcing download: {}", err),
+            res => res.map_err(Error::Suffix)?,
+        }
+        if firmware.starts_with(dfuse_file::PREFIX_SIGNATURE) {
+            let file = dfuse_file::DfuseFile::parse(firmware).map_err(Error::DfuseFile)?;
+            self.download_dfuse_file(&file)
+        } else {
+            self.download_from_slice(firmware)
+        }
+    }
+
+    /// Download the target of a DfuSe file matching the alternate setting of the device.
+    ///
+    /// All the image elements of the target are downloaded at their own address in one session,
+    /// sorted by address, and the device manifests once at the end.
+    /// Returns whether the device stayed on the bus, was reset or was detached.
+    pub fn download_dfuse_file(
+        self,
+        file: &dfuse_file::DfuseFile<'_>,
+    ) -> Result<DownloadOutcome<Self>, IO::Error> {
+        if !matches!(self.io.protocol(), DfuProtocol::Dfuse { .. }) {
+            return Err(Error::DfuseNotSupported.into());
+        }
+        let alt_setting = self.io.alt_setting();
+        let target = file
+            .target(alt_setting)
+            .ok_or(Error::MissingDfuseTarget { alt_setting })?;
+        let mut elements = target
+            .elements()
+            .filter(|element| !element.data.is_empty())
+            .map(|element| {
+                let length =
+                    u32::try_from(element.data.len()).map_err(|_| Error::OutOfCapabilities)?;
+                Ok((element.address, element.data, length))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        elements.sort_by_key(|&(address, _, _)| address);
+        self.dfu.check_segments(
+            self.io.protocol(),
+            elements
+                .iter()
+                .map(|&(address, _, length)| (address, length)),
+        )?;
+
+        let mut elements = elements.into_iter();
+        match elements.next() {
+            Some((address, data, length)) => {
+                self.download_segments(Some(address), data, length, elements, None)
+            }
+            None => Ok(DownloadOutcome::Done(self)),
+        }
+    }
+
+    /// Download the segments of an Intel HEX file.
+    ///
+    /// All the segments are downloaded at their own address in one session and the device
+    /// manifests once at the end. With [`Self::with_leave`], the device jumps to the start address
+    /// of the file if it has one, otherwise to the address of the first segment.
+    /// Returns whether the device stayed on the bus, was reset or was detached.
+    pub fn download_intel_hex(
+        self,
+        hex: &intel_hex::IntelHex,
+    ) -> Result<DownloadOutcome<Self>, IO::Error> {
+        if !matches!(self.io.protocol(), DfuProtocol::Dfuse { .. }) {
+            return Err(Error::DfuseNotSupported.into());
+        }
+        let segments = hex
+            .segments()
+            .iter()
+            .map(|segment| {
+                let length =
+                    u32::try_from(segment.data.len()).map_err(|_| Error::OutOfCapabilities)?;
+                Ok((segment.address, segment.data.as_slice(), length))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.dfu.check_segments(
+            self.io.protocol(),
+            segments
+                .iter()
+                .map(|&(address, _, length)| (address, length)),
+        )?;
+
+        let mut segments = segments.into_iter();
+        match segments.next() {
+            Some((address, data, length)) => {
+                self.download_segments(Some(address), data, length, segments, hex.start_address())
+            }
+            None => Ok(DownloadOutcome::Done(self)),
+        }
+    }
+
+    /// Download segments, the first one at `address` (or the default address of the device) and
+    /// the others at their own address, in one session.
+    fn download_segments<R: std::io::Read>(
         mut self,
+        address: Option<u32>,
         reader: R,
         length: u32,
-    ) -> Result<Option<Self>, IO::Error> {
+        mut segments: impl Iterator<Item = (u32, R, u32)>,
+        leave_address: Option<u32>,
+    ) -> Result<DownloadOutcome<Self>, IO::Error> {
         let transfer_size = self.io.functional_descriptor().transfer_size as usize;
         let mut reader = Buffer::new(transfer_size, reader);
         let buffer = reader.fill_buf()?;
         if buffer.is_empty() {
-            return Ok(Some(self));
-        }
-
-        macro_rules! wait_status {
-            ($cmd:expr) => {{
-                let mut cmd = $cmd;
-                loop {
-                    cmd = match cmd.next() {
-                        get_status::Step::Break(cmd) => break cmd,
-                        get_status::Step::Wait(cmd, poll_timeout) => {
-                            std::thread::sleep(std::time::Duration::from_millis(poll_timeout));
-                            let (cmd, mut control) = cmd.get_status(&mut self.buffer);
-                            let n = control.execute(&self.io)?;
-                            cmd.chain(&self.buffer[..n as usize])??
-                        }
-                    };
-                }
-            }};
+            return Ok(DownloadOutcome::Done(self));
         }
 
-        let cmd = self.dfu.download(self.io.protocol(), length)?;
-        let (cmd, mut control) = cmd.get_status(&mut self.buffer);
-        let n = control.execute(&self.io)?;
-        let (cmd, control) = cmd.chain(&self.buffer[..n])?;
-        if let Some(control) = control {
-            control.execute(&self.io)?;
+        let descriptor = self.io.functional_descriptor();
+        let dfuse = matches!(self.io.protocol(), DfuProtocol::Dfuse { .. });
+        if self.verify && !descriptor.can_upload {
+            return Err(Error::UploadNotSupported.into());
         }
-        let (cmd, mut control) = cmd.get_status(&mut self.buffer);
-        let n = control.execute(&self.io)?;
-        let mut download_loop = cmd.chain(&self.buffer[..n])??;
+        if self.verify && !dfuse && !descriptor.manifestation_tolerant {
+            return Err(Error::VerificationNotSupported.into());
+        }
+        if self.leave && !dfuse {
+            return Err(Error::DfuseNotSupported.into());
+        }
+        // Data written to the device for each segment, kept for the verification.
+        let mut written = vec![(address, Vec::new())];
+        // The address pointer must be set to the start address again before manifesting, after
+        // reading back the data or downloading other segments.
+        let mut reset_dfuse = self.verify && dfuse;
+
+        let cmd = self.dfu.download_at(self.io.protocol(), address, length)?;
+        let mut download_loop = describe_error(&self.io, reach_idle!(&self.io, self.buffer, cmd))?;
 
         loop {
             download_loop = match download_loop.next() {
-                download::Step::Break => break Ok(Some(self)),
+                download::Step::Break => {
+                    if self.verify && !dfuse {
+                        for (address, written) in &written {
+                            verify(&self.io, &self.dfu, &mut self.buffer, *address, written)?;
+                        }
+                    }
+                    break Ok(DownloadOutcome::Done(self));
+                }
                 download::Step::Erase(cmd) => {
                     let (cmd, control) = cmd.erase()?;
                     control.execute(&self.io)?;
-                    wait_status!(cmd)
+                    wait_status!(&self.io, self.buffer, cmd)
+                }
+                download::Step::EraseAll(cmd) => {
+                    let (cmd, control) = cmd.erase();
+                    control.execute(&self.io)?;
+                    wait_status!(&self.io, self.buffer, cmd)
                 }
                 download::Step::SetAddress(cmd) => {
                     let (cmd, control) = cmd.set_address();
                     control.execute(&self.io)?;
-                    wait_status!(cmd)
+                    wait_status!(&self.io, self.buffer, cmd)
                 }
                 download::Step::DownloadChunk(cmd) => {
                     let chunk = reader.fill_buf()?;
+                    if chunk.is_empty() {
+                        if let Some((address, next, length)) = segments.next() {
+                            reader = Buffer::new(transfer_size, next);
+                            written.push((Some(address), Vec::new()));
+                            download_loop = cmd.next_segment(address, length)?;
+                            reset_dfuse = true;
+                            continue;
+                        }
+                    }
+                    if chunk.is_empty() && reset_dfuse {
+                        reset_dfuse = false;
+                        if self.verify {
+                            // Verify before leaving DFU mode
+                            for (address, written) in &written {
+                                verify(&self.io, &self.dfu, &mut self.buffer, *address, written)?;
+                            }
+                        }
+                        download_loop = cmd.reset_address();
+                        continue;
+                    }
+                    if chunk.is_empty() && self.leave {
+                        let cmd = match leave_address {
+                            Some(address) => cmd.leave_at(address),
+                            None => cmd.leave(),
+                        }
+                        .ok_or(Error::DfuseNotSupported)?;
+                        leave(&self.io, &mut self.buffer, cmd)?;
+                        break Ok(DownloadOutcome::Reset);
+                    }
                     let (cmd, control) = cmd.download(chunk)?;
                     let n = control.execute(&self.io)?;
+                    if let Some((_, written)) = written.last_mut().filter(|_| self.verify) {
+                        written.extend_from_slice(&chunk[..n]);
+                    }
                     reader.consume(n);
                     if let Some(progress) = self.progress.as_mut() {
                         progress(n);
                     }
-                    wait_status!(cmd)
+                    wait_status!(&self.io, self.buffer, cmd)
                 }
                 download::Step::UsbReset => {
                     log::trace!("Device reset");
                     self.io.usb_reset()?;
-                    break Ok(None);
+                    break Ok(DownloadOutcome::Reset);
+                }
+                download::Step::Detach(cmd) => {
+                    let (detached, control) = cmd.detach();
+                    control.execute(&self.io)?;
+                    if detached.usb_reset {
+                        log::trace!("Device reset");
+                        self.io.usb_reset()?;
+                    }
+                    break Ok(DownloadOutcome::Detached);
                 }
             }
         }
@@ -188,24 +435,133 @@ where
 
     /// Download a firmware into the device.
     ///
-    /// The length is inferred from the reader. Returns `Some(Self)` if the device stayed on the
-    /// bus (manifestation tolerant, no USB reset occurred) or `None` if a USB reset was performed.
+    /// The length is inferred from the reader.
+    /// Returns whether the device stayed on the bus, was reset or was detached.
     pub fn download_all<R: std::io::Read + std::io::Seek>(
         self,
         mut reader: R,
-    ) -> Result<Option<Self>, IO::Error> {
+    ) -> Result<DownloadOutcome<Self>, IO::Error> {
         let length = u32::try_from(reader.seek(std::io::SeekFrom::End(0))?)
             .map_err(|_| Error::MaximumTransferSizeExceeded)?;
         reader.seek(std::io::SeekFrom::Start(0))?;
         self.download(reader, length)
     }
 
-    /// Send a Detach request to the device
-    pub fn detach(&self) -> Result<(), IO::Error> {
-        self.dfu.detach().execute(&self.io)?;
+    /// Upload a firmware from the device into a writer.
+    ///
+    /// At most `max_len` bytes are read, the upload ends earlier if the device has no more data to
+    /// send. Returns the number of bytes uploaded.
+    ///
+    /// With DfuSe, the memory is read from the address set by [`Self::override_address`] or the
+    /// start address of the device, and the whole `max_len` range must fit in the memory layout
+    /// and be readable: the upload fails with [`Error::AddressOutOfRange`] or
+    /// [`Error::MissingPageAttribute`] before reading anything otherwise.
+    pub fn upload<W: std::io::Write>(&mut self, writer: W, max_len: u32) -> Result<u32, IO::Error> {
+        upload(
+            &self.io,
+            &self.dfu,
+            &mut self.buffer,
+            self.progress.as_mut(),
+            writer,
+            None,
+            max_len,
+        )
+    }
+
+    /// Query the status of the device.
+    ///
+    /// Note that the device may change its state as a result of this request.
+    pub fn status(&mut self) -> Result<get_status::GetStatusMessage, IO::Error> {
+        let cmd = self.dfu.get_status();
+        let (cmd, mut control) = cmd.get_status(&mut self.buffer);
+        let n = control.execute(&self.io)?;
+        Ok(cmd.chain(&self.buffer[..n])?)
+    }
+
+    /// Query the state of the device.
+    pub fn state(&mut self) -> Result<State, IO::Error> {
+        let cmd = self.dfu.get_state();
+        let (cmd, mut control) = cmd.get_state(&mut self.buffer);
+        let n = control.execute(&self.io)?;
+        Ok(cmd.chain(&self.buffer[..n])?)
+    }
+
+    /// Abort the current operation and bring the device back to `dfuIDLE`.
+    pub fn abort(&mut self) -> Result<(), IO::Error> {
+        abort(&self.io, &mut self.buffer, self.dfu.abort())
+    }
+
+    /// Query the DfuSe commands supported by the device.
+    pub fn get_commands(&mut self) -> Result<dfuse::Commands, IO::Error> {
+        let cmd = self.dfu.get_commands(self.io.protocol())?;
+        let cmd = describe_error(&self.io, reach_idle!(&self.io, self.buffer, cmd))?;
+        let (cmd, control) = cmd.request();
+        control.execute(&self.io)?;
+        let cmd = wait_status!(&self.io, self.buffer, cmd);
+        let (cmd, control) = cmd.abort();
+        control.execute(&self.io)?;
+        let cmd = wait_status!(&self.io, self.buffer, cmd);
+        let (cmd, mut control) = cmd.upload(&mut self.buffer);
+        let n = control.execute(&self.io)?;
+        let (commands, cmd) = cmd.chain(&self.buffer[..n]);
+        abort(&self.io, &mut self.buffer, cmd)?;
+        Ok(commands)
+    }
+
+    /// Erase the whole memory of the device with DfuSe.
+    pub fn mass_erase(&mut self) -> Result<(), IO::Error> {
+        let cmd = self.dfu.mass_erase(self.io.protocol())?;
+        let cmd = describe_error(&self.io, reach_idle!(&self.io, self.buffer, cmd))?;
+        let (cmd, control) = cmd.request();
+        control.execute(&self.io)?;
+        wait_status!(&self.io, self.buffer, cmd);
+        Ok(())
+    }
+
+    /// Remove the read protection of the device with the DfuSe Read Unprotect command.
+    ///
+    /// The device erases its whole memory and resets, it must be opened again afterwards. If the
+    /// device resets before answering the last status request, the error is returned unless
+    /// [`DfuIo::is_disconnected`] recognizes it.
+    pub fn read_unprotect(mut self) -> Result<(), IO::Error> {
+        let cmd = self.dfu.read_unprotect(self.io.protocol())?;
+        let cmd = describe_error(&self.io, reach_idle!(&self.io, self.buffer, cmd))?;
+        let (cmd, control) = cmd.request();
+        control.execute(&self.io)?;
+        let (cmd, mut control) = cmd.get_status(&mut self.buffer);
+        match control.execute(&self.io) {
+            Ok(n) => describe_error(&self.io, cmd.chain(&self.buffer[..n])?)?,
+            // The device may reset before answering
+            Err(err) if self.io.is_disconnected(&err) => log::trace!("Device disconnected"),
+            Err(err) => return Err(err),
+        }
         Ok(())
     }
 
+    /// Leave DFU mode and jump to this address with DfuSe.
+    ///
+    /// The device runs the firmware without a USB reset, it must be opened again afterwards. If the
+    /// device jumps before answering the last status request, the error is returned unless
+    /// [`DfuIo::is_disconnected`] recognizes it.
+    pub fn leave(mut self, address: u32) -> Result<(), IO::Error> {
+        let cmd = self.dfu.leave(self.io.protocol(), address)?;
+        let cmd = describe_error(&self.io, reach_idle!(&self.io, self.buffer, cmd))?;
+        leave(&self.io, &mut self.buffer, cmd)
+    }
+
+    /// Detach the device from runtime mode (`appIDLE`).
+    ///
+    /// Returns `true` if the device does not detach by itself and must be reset with
+    /// [`Self::usb_reset`] to enter DFU mode.
+    pub fn detach(&mut self) -> Result<bool, IO::Error> {
+        let cmd = self.dfu.detach();
+        let (cmd, mut control) = cmd.get_status(&mut self.buffer);
+        let n = control.execute(&self.io)?;
+        let (detached, control) = describe_error(&self.io, cmd.chain(&self.buffer[..n])?)?;
+        control.execute(&self.io)?;
+        Ok(detached.usb_reset)
+    }
+
     /// Reset the USB device
     pub fn usb_reset(self) -> Result<IO::Reset, IO::Error> {
         self.io.usb_reset()
@@ -221,3 +577,154 @@ where
         self.io.functional_descriptor().manifestation_tolerant
     }
 }
+
+fn upload<IO, E, W>(
+    io: &IO,
+    dfu: &DfuSansIo,
+    buffer: &mut [u8],
+    mut progress: Option<&mut Box<dyn FnMut(usize)>>,
+    mut writer: W,
+    address: Option<u32>,
+    length: u32,
+) -> Result<u32, E>
+where
+    IO: DfuIo<Read = usize, Write = usize, Reset = (), Error = E>,
+    E: From<std::io::Error> + From<Error>,
+    W: std::io::Write,
+{
+    let cmd = dfu.upload_at(io.protocol(), address, length)?;
+    let mut upload_loop = describe_error(io, reach_idle!(io, buffer, cmd))?;
+    let mut uploaded = 0;
+
+    loop {
+        upload_loop = match upload_loop.next() {
+            upload::Step::Break => break Ok(uploaded),
+            upload::Step::SetAddress(cmd) => {
+                let (cmd, control) = cmd.set_address();
+                control.execute(io)?;
+                wait_status!(io, buffer, cmd)
+            }
+            upload::Step::Abort(cmd) => {
+                let (cmd, control) = cmd.abort();
+                control.execute(io)?;
+                wait_status!(io, buffer, cmd)
+            }
+            upload::Step::UploadChunk(cmd) => {
+                let (cmd, mut control) = cmd.upload(buffer);
+                let n = control.execute(io)?;
+                let upload_loop = cmd.chain(&buffer[..n])?;
+                writer.write_all(&buffer[..n])?;
+                uploaded += n as u32;
+                if let Some(progress) = progress.as_mut() {
+                    progress(n);
+                }
+                upload_loop
+            }
+        }
+    }
+}
+
+fn abort<IO, E>(
+    io: &IO,
+    buffer: &mut [u8],
+    cmd: get_status::GetStatus<get_status::Abort<get_status::WaitState<()>>>,
+) -> Result<(), E>
+where
+    IO: DfuIo<Read = usize, Write = usize, Reset = (), Error = E>,
+    E: From<std::io::Error> + From<Error>,
+{
+    let (cmd, mut control) = cmd.get_status(buffer);
+    let n = control.execute(io)?;
+    let (cmd, control) = cmd.chain(&buffer[..n])?;
+    if let Some(control) = control {
+        control.execute(io)?;
+    }
+    wait_status!(io, buffer, cmd);
+    Ok(())
+}
+
+fn leave<IO, E>(io: &IO, buffer: &mut [u8], cmd: dfuse::LeaveSetAddress) -> Result<(), E>
+where
+    IO: DfuIo<Read = usize, Write = usize, Reset = (), Error = E>,
+    E: From<std::io::Error> + From<Error>,
+{
+    let (cmd, control) = cmd.set_address();
+    control.execute(io)?;
+    let cmd = wait_status!(io, buffer, cmd);
+    let (cmd, control) = cmd.request();
+    control.execute(io)?;
+    let (cmd, mut control) = cmd.get_status(buffer);
+    match control.execute(io) {
+        Ok(n) => describe_error(io, cmd.chain(&buffer[..n])?)?,
+        // The device may jump before answering
+        Err(err) if io.is_disconnected(&err) => log::trace!("Device disconnected"),
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
+
+/// Attach the string descriptor of a status error to the error, if the device provides one.
+fn describe_error<IO, E, T>(io: &IO, result: Result<T, Error>) -> Result<T, E>
+where
+    IO: DfuIo<Error = E>,
+    E: From<Error>,
+{
+    match result {
+        Err(Error::StatusError {
+            status,
+            state,
+            index,
+        }) if index != 0 => match io.get_string_descriptor(index) {
+            Ok(Some(description)) => Err(Error::StatusErrorDescription {
+                status,
+                state,
+                description,
+            }
+            .into()),
+            Ok(None) | Err(_) => {
+                log::trace!("No string descriptor for index {}", index);
+                Err(Error::StatusError {
+                    status,
+                    state,
+                    index,
+                }
+                .into())
+            }
+        },
+        result => Ok(result?),
+    }
+}
+
+fn verify<IO, E>(
+    io: &IO,
+    dfu: &DfuSansIo,
+    buffer: &mut [u8],
+    address: Option<u32>,
+    written: &[u8],
+) -> Result<(), E>
+where
+    IO: DfuIo<Read = usize, Write = usize, Reset = (), Error = E>,
+    E: From<std::io::Error> + From<Error>,
+{
+    log::trace!("Verifying downloaded data");
+    let mut uploaded = Vec::with_capacity(written.len());
+    upload(
+        io,
+        dfu,
+        buffer,
+        None,
+        &mut uploaded,
+        address,
+        written.len() as u32,
+    )?;
+    // Without DfuSe, the mismatch is reported at its offset in the firmware
+    let address = match io.protocol() {
+        DfuProtocol::Dfu => 0,
+        DfuProtocol::Dfuse {
+            address: start_address,
+            ..
+        } => dfu.dfuse_address(*start_address, address),
+    };
+    upload::verify(address, written, &uploaded)?;
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use functional_descriptor::FunctionalDescriptor;
+
+use super::*;
+
+const REQUEST_TYPE: u8 = 0b00100001;
+const DFU_DNLOAD: u8 = 1;
+const DFU_UPLOAD: u8 = 2;
+const DFU_ABORT: u8 = 6;
+
+/// Starting point to upload a firmware from a device.
+#[must_use]
+pub struct Start<'dfu> {
+    pub(crate) descriptor: &'dfu FunctionalDescriptor,
+    pub(crate) end_pos: u32,
+    pub(crate) protocol: ProtocolData,
+}
+
+impl<'dfu> ChainedCommand for Start<'dfu> {
+    type Arg = get_status::GetStatusMessage;
+    type Into = Result<UploadLoop<'dfu>, Error>;
+
+    fn chain(self, message: Self::Arg) -> Self::Into {
+        message.check_status()?;
+        let state = message.state;
+        log::trace!("Starting upload process");
+        if state == State::DfuIdle {
+            let block_num = match self.protocol {
+                ProtocolData::Dfu => 0,
+                ProtocolData::Dfuse { .. } => 2,
+            };
+            Ok(UploadLoop {
+                descriptor: self.descriptor,
+                protocol: self.protocol,
+                state,
+                end_pos: self.end_pos,
+                copied_pos: 0,
+                block_num,
+                eof: false,
+            })
+        } else {
+            Err(Error::InvalidState {
+                got: state,
+                expected: State::DfuIdle,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum ProtocolData {
+    Dfu,
+    Dfuse { address: u32, address_set: bool },
+}
+
+/// Upload loop.
+#[must_use]
+pub struct UploadLoop<'dfu> {
+    descriptor: &'dfu FunctionalDescriptor,
+    protocol: ProtocolData,
+    // Expected state of the device at this point of the loop.
+    state: State,
+    end_pos: u32,
+    copied_pos: u32,
+    block_num: u16,
+    eof: bool,
+}
+
+impl<'dfu> UploadLoop<'dfu> {
+    /// Get the next step in the upload loop.
+    pub fn next(self) -> Step<'dfu> {
+        if self.eof {
+            // A short frame terminates the upload, the device is back in dfuIDLE.
+            log::trace!("Upload loop ended");
+            return Step::Break;
+        }
+
+        match self.protocol {
+            ProtocolData::Dfuse {
+                address,
+                address_set: false,
+            } => {
+                log::trace!("Upload loop: set address");
+                return Step::SetAddress(SetAddress {
+                    descriptor: self.descriptor,
+                    end_pos: self.end_pos,
+                    address,
+                    block_num: self.block_num,
+                });
+            }
+            ProtocolData::Dfuse { .. } if self.state == State::DfuDnloadIdle => {
+                log::trace!("Upload loop: abort after setting the address");
+                return Step::Abort(Abort {
+                    descriptor: self.descriptor,
+                    protocol: self.protocol,
+                    end_pos: self.end_pos,
+                    copied_pos: self.copied_pos,
+                    block_num: self.block_num,
+                });
+            }
+            _ => {}
+        }
+
+        if self.copied_pos >= self.end_pos {
+            if self.state == State::DfuUploadIdle {
+                // The device has more data to send, abort to bring it back to dfuIDLE
+                log::trace!("Upload loop: abort after reaching the requested length");
+                Step::Abort(Abort {
+                    descriptor: self.descriptor,
+                    protocol: self.protocol,
+                    end_pos: self.end_pos,
+                    copied_pos: self.copied_pos,
+                    block_num: self.block_num,
+                })
+            } else {
+                log::trace!("Upload loop ended: requested length reached");
+                Step::Break
+            }
+        } else {
+            log::trace!("Upload loop: upload chunk");
+            Step::UploadChunk(UploadChunk {
+                descriptor: self.descriptor,
+                protocol: self.protocol,
+                end_pos: self.end_pos,
+                copied_pos: self.copied_pos,
+                block_num: self.block_num,
+            })
+        }
+    }
+}
+
+/// Upload step in the loop.
+#[allow(missing_docs)]
+pub enum Step<'dfu> {
+    Break,
+    SetAddress(SetAddress<'dfu>),
+    Abort(Abort<'dfu>),
+    UploadChunk(UploadChunk<'dfu>),
+}
+
+/// Set the address to upload from.
+#[must_use]
+pub struct SetAddress<'dfu> {
+    descriptor: &'dfu FunctionalDescriptor,
+    end_pos: u32,
+    address: u32,
+    block_num: u16,
+}
+
+impl<'dfu> SetAddress<'dfu> {
+    /// Set the address to upload from.
+    pub fn set_address(
+        self,
+    ) -> (
+        get_status::WaitState<UploadLoop<'dfu>>,
+        UsbWriteControl<[u8; 5]>,
+    ) {
+        let next = get_status::WaitState::new(
+            State::DfuDnbusy,
+            State::DfuDnloadIdle,
+            UploadLoop {
+                descriptor: self.descriptor,
+                protocol: ProtocolData::Dfuse {
+                    address: self.address,
+                    address_set: true,
+                },
+                state: State::DfuDnloadIdle,
+                end_pos: self.end_pos,
+                copied_pos: 0,
+                block_num: self.block_num,
+                eof: false,
+            },
+        );
+        let control = UsbWriteControl::new(
+            REQUEST_TYPE,
+            DFU_DNLOAD,
+            0,
+            <[u8; 5]>::from(download::DownloadCommandSetAddress(self.address)),
+        );
+
+        (next, control)
+    }
+}
+
+/// Abort the current operation to bring the device back to `dfuIDLE`.
+#[must_use]
+pub struct Abort<'dfu> {
+    descriptor: &'dfu FunctionalDescriptor,
+    protocol: ProtocolData,
+    end_pos: u32,
+    copied_pos: u32,
+    block_num: u16,
+}
+
+impl<'dfu> Abort<'dfu> {
+    /// Abort the current operation to bring the device back to `dfuIDLE`.
+    pub fn abort(
+        self,
+    ) -> (
+        get_status::WaitState<UploadLoop<'dfu>>,
+        UsbWriteControl<[u8; 0]>,
+    ) {
+        let next = get_status::WaitState::new(
+            State::DfuIdle,
+            State::DfuIdle,
+            UploadLoop {
+                descriptor: self.descriptor,
+                protocol: self.protocol,
+                state: State::DfuIdle,
+                end_pos: self.end_pos,
+                copied_pos: self.copied_pos,
+                block_num: self.block_num,
+                eof: false,
+            },
+        );
+        let control = UsbWriteControl::new(REQUEST_TYPE, DFU_ABORT, 0, []);
+
+        (next, control)
+    }
+}
+
+/// Upload a chunk of data from the device.
+#[must_use]
+pub struct UploadChunk<'dfu> {
+    descriptor: &'dfu FunctionalDescriptor,
+    protocol: ProtocolData,
+    end_pos: u32,
+    copied_pos: u32,
+    block_num: u16,
+}
+
+impl<'dfu> UploadChunk<'dfu> {
+    /// Upload a chunk of data from the device.
+    ///
+    /// The buffer must be at least as big as the transfer size of the device. With DfuSe, the
+    /// address of the chunk is computed by the device from the block number and the transfer
+    /// size.
+    pub fn upload(self, buffer: &'_ mut [u8]) -> (UploadRecv<'dfu>, UsbReadControl<'_>) {
+        let transfer_size = self.descriptor.transfer_size as u32;
+        log::trace!("Transfer size: {}", transfer_size);
+        let len = (self.end_pos - self.copied_pos).min(transfer_size);
+        debug_assert!(buffer.len() >= len as usize);
+        log::trace!("Chunk length: {}", len);
+        log::trace!("Copied position: {}", self.copied_pos);
+        log::trace!("Block number: {}", self.block_num);
+
+        let next = UploadRecv {
+            descriptor: self.descriptor,
+            protocol: self.protocol,
+            end_pos: self.end_pos,
+            copied_pos: self.copied_pos,
+            block_num: self.block_num,
+            requested: len,
+        };
+        let control = UsbReadControl::new(
+            REQUEST_TYPE,
+            DFU_UPLOAD,
+            self.block_num,
+            &mut buffer[..len as usize],
+        );
+
+        (next, control)
+    }
+}
+
+/// Read a chunk of data after getting it from the device.
+#[must_use]
+pub struct UploadRecv<'dfu> {
+    descriptor: &'dfu FunctionalDescriptor,
+    protocol: ProtocolData,
+    end_pos: u32,
+    copied_pos: u32,
+    block_num: u16,
+    requested: u32,
+}
+
+impl<'dfu> UploadRecv<'dfu> {
+    /// Chain this command into another.
+    pub fn chain(self, bytes: &[u8]) -> Result<UploadLoop<'dfu>, Error> {
+        log::trace!("Received chunk length: {}", bytes.len());
+        let len = u32::try_from(bytes.len())
+            .ok()
+            .filter(|&len| len <= self.requested)
+            .ok_or(Error::BufferTooBig {
+                got: bytes.len(),
+                expected: self.requested as usize,
+            })?;
+
+        let eof = len < self.requested;
+        Ok(UploadLoop {
+            descriptor: self.descriptor,
+            protocol: self.protocol,
+            state: if eof {
+                State::DfuIdle
+            } else {
+                State::DfuUploadIdle
+            },
+            end_pos: self.end_pos,
+            copied_pos: self
+                .copied_pos
+                .checked_add(len)
+                .ok_or(Error::MaximumTransferSizeExceeded)?,
+            block_num: self.block_num.wrapping_add(1),
+            eof,
+        })
+    }
+}
+
+/// Compare uploaded data with the data expected in the device at `address`.
+#[cfg(any(feature = "std", test))]
+pub(crate) fn verify(address: u32, expected: &[u8], uploaded: &[u8]) -> Result<(), Error> {
+    let offset = expected
+        .iter()
+        .zip(uploaded)
+        .position(|(a, b)| a != b)
+        .or_else(|| (uploaded.len() < expected.len()).then_some(uploaded.len()));
+
+    match offset {
+        Some(offset) => {
+            let address = address.wrapping_add(offset as u32);
+            log::trace!("Verification failed at {:#x}", address);
+            Err(Error::VerificationFailed { address })
+        }
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,584 @@
+use dfu_core::dfuse_file::{DfuseFile, Element, Writer};
+use dfu_core::intel_hex::IntelHex;
+
+mod mock;
+
+fn setup() {
+    let _ = env_logger::builder()
+        .is_test(true)
+        .filter_level(log::LevelFilter::Trace)
+        .parse_default_env()
+        .try_init();
+}
+
+#[test]
+fn get_commands() {
+    setup();
+    let mock = mock::MockIOBuilder::default().dfuse(true).build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    let commands = dfu.get_commands().unwrap();
+    assert_eq!(
+        commands,
+        dfu_core::dfuse::Commands {
+            get: true,
+            set_address: true,
+            erase: true,
+            read_unprotect: true,
+        }
+    );
+    assert_eq!(mock_data.state(), dfu_core::State::DfuIdle);
+}
+
+#[test]
+fn get_commands_not_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default().build();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    assert!(matches!(
+        dfu.get_commands(),
+        Err(mock::Error::Dfu(dfu_core::Error::DfuseNotSupported))
+    ));
+}
+
+#[test]
+fn read_unprotect() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .memory(vec![0xaa; 16])
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    dfu.read_unprotect().unwrap();
+    assert!(mock_data.disconnected());
+    assert!(mock_data.downloaded().is_empty());
+}
+
+#[test]
+fn read_unprotect_vanished() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .vanish(std::io::ErrorKind::NotConnected)
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    dfu.read_unprotect().unwrap();
+    assert!(mock_data.disconnected());
+}
+
+#[test]
+fn read_unprotect_status_error() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .vanish(std::io::ErrorKind::TimedOut)
+        .build();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    assert!(matches!(
+        dfu.read_unprotect(),
+        Err(mock::Error::IO(err)) if err.kind() == std::io::ErrorKind::TimedOut
+    ));
+}
+
+#[test]
+fn read_unprotect_not_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default().build();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    assert!(matches!(
+        dfu.read_unprotect(),
+        Err(mock::Error::Dfu(dfu_core::Error::DfuseNotSupported))
+    ));
+}
+
+#[test]
+fn mass_erase() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .memory(vec![0xaa; 16])
+        .build();
+    let size = mock.size();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    dfu.mass_erase().unwrap();
+    assert_eq!(mock_data.erased(), vec![(0, size)]);
+    assert!(mock_data.downloaded().is_empty());
+    assert_eq!(mock_data.state(), dfu_core::State::DfuDnloadIdle);
+}
+
+#[test]
+fn download_with_mass_erase() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .manifestation_tolerant(true)
+        .build();
+    let size = mock.size();
+    let firmware: Vec<u8> = (0..size).map(|i| i as u8).collect();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_mass_erase(true);
+
+    dfu.download_from_slice(&firmware).unwrap();
+    assert_eq!(mock_data.erased(), vec![(0, size)]);
+    assert!(mock_data.completed());
+    assert_eq!(firmware, mock_data.downloaded());
+}
+
+#[test]
+fn mass_erase_not_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default().build();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    assert!(matches!(
+        dfu.mass_erase(),
+        Err(mock::Error::Dfu(dfu_core::Error::DfuseNotSupported))
+    ));
+}
+
+#[test]
+fn leave() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    dfu.leave(0x08000100).unwrap();
+    assert_eq!(mock_data.jumped(), Some(0x08000100));
+    assert!(mock_data.disconnected());
+}
+
+#[test]
+fn leave_vanished() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .vanish(std::io::ErrorKind::NotConnected)
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    dfu.leave(0x08000100).unwrap();
+    assert_eq!(mock_data.jumped(), Some(0x08000100));
+}
+
+#[test]
+fn leave_status_error() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .vanish(std::io::ErrorKind::TimedOut)
+        .build();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    assert!(matches!(
+        dfu.leave(0x08000100),
+        Err(mock::Error::IO(err)) if err.kind() == std::io::ErrorKind::TimedOut
+    ));
+}
+
+#[test]
+fn download_and_leave() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let firmware: Vec<u8> = (0..mock.size()).map(|i| i as u8).collect();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_leave(true).with_verify(true);
+
+    assert!(matches!(
+        dfu.download_from_slice(&firmware).unwrap(),
+        dfu_core::DownloadOutcome::Reset
+    ));
+    assert_eq!(firmware, mock_data.downloaded());
+    assert_eq!(mock_data.jumped(), Some(0x08000000));
+    assert!(!mock_data.was_reset());
+}
+
+#[test]
+fn download_and_leave_not_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default().build();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_leave(true);
+
+    assert!(matches!(
+        dfu.download_from_slice(&[0; 16]),
+        Err(mock::Error::Dfu(dfu_core::Error::DfuseNotSupported))
+    ));
+}
+
+#[test]
+fn download_to_page_not_erasable() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .memory_layout("15*4 g,1*4 e,8*8 g")
+        .build();
+    let firmware: Vec<u8> = (0..mock.size()).map(|i| i as u8).collect();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    assert!(matches!(
+        dfu.download_from_slice(&firmware),
+        Err(mock::Error::Dfu(dfu_core::Error::MissingPageAttribute {
+            address: 0x0800003c,
+            attribute: dfu_core::memory_layout::Attributes::ERASABLE,
+        }))
+    ));
+    assert!(mock_data.erased().is_empty());
+    assert!(mock_data.downloaded().is_empty());
+}
+
+#[test]
+fn download_to_page_not_erasable_with_mass_erase() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .memory_layout("15*4 g,1*4 e,8*8 g")
+        .build();
+    let firmware: Vec<u8> = (0..mock.size()).map(|i| i as u8).collect();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_mass_erase(true);
+
+    dfu.download_from_slice(&firmware).unwrap();
+    assert_eq!(firmware, mock_data.downloaded());
+}
+
+#[test]
+fn download_to_page_not_writeable() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .memory_layout("16*4 g,8*8 c")
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.override_address(0x08000038);
+
+    assert!(matches!(
+        dfu.download_from_slice(&[0; 16]),
+        Err(mock::Error::Dfu(dfu_core::Error::MissingPageAttribute {
+            address: 0x08000040,
+            attribute: dfu_core::memory_layout::Attributes::WRITEABLE,
+        }))
+    ));
+    assert!(mock_data.erased().is_empty());
+}
+
+/// A DfuSe file with unsorted elements, two of them sharing a page.
+fn dfuse_file() -> Vec<u8> {
+    Writer::new()
+        .add_target(
+            0,
+            Some("Internal Flash"),
+            [
+                Element {
+                    address: 0x08000046,
+                    data: &[0xc0; 10],
+                },
+                Element {
+                    address: 0x08000002,
+                    data: &[0xb0; 8],
+                },
+                Element {
+                    address: 0x08000042,
+                    data: &[0xa0; 2],
+                },
+            ],
+        )
+        .to_vec()
+}
+
+fn hex_record(offset: u16, record_type: u8, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&offset.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+    bytes.push(bytes.iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte)));
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!(":{}\n", hex)
+}
+
+// Same memory as `dfuse_file`, with a start address
+fn intel_hex() -> IntelHex {
+    let src = [
+        hex_record(0, 0x04, &[0x08, 0x00]),
+        hex_record(0x46, 0x00, &[0xc0; 10]),
+        hex_record(0x02, 0x00, &[0xb0; 8]),
+        hex_record(0x42, 0x00, &[0xa0; 2]),
+        hex_record(0, 0x05, &0x08000199u32.to_be_bytes()),
+        hex_record(0, 0x01, &[]),
+    ]
+    .concat();
+    IntelHex::parse(&src).unwrap()
+}
+
+fn dfuse_file_memory() -> Vec<u8> {
+    [
+        vec![0xff; 2],
+        vec![0xb0; 8],
+        vec![0xff; 56],
+        vec![0xa0; 2],
+        vec![0xff; 2],
+        vec![0xc0; 10],
+    ]
+    .concat()
+}
+
+#[test]
+fn download_dfuse_file() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_verify(true);
+
+    let bytes = dfuse_file();
+    let file = DfuseFile::parse(&bytes).unwrap();
+    dfu.download_dfuse_file(&file).unwrap();
+
+    // The page shared by the last two elements is erased once
+    assert_eq!(
+        mock_data.erased(),
+        &[(0, 4), (4, 4), (8, 4), (64, 8), (72, 8)]
+    );
+    assert_eq!(mock_data.downloaded(), dfuse_file_memory());
+    assert_eq!(mock_data.manifested_at(), Some(0x08000002));
+    assert!(mock_data.completed());
+}
+
+#[test]
+fn download_dfuse_file_with_suffix() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    let mut bytes = dfuse_file();
+    dfu_core::suffix::Builder::new()
+        .dfu_version(dfu_core::suffix::DFUSE_VERSION)
+        .append(&mut bytes);
+    dfu.download_file(&bytes).unwrap();
+
+    assert_eq!(mock_data.downloaded(), dfuse_file_memory());
+    assert_eq!(mock_data.manifested_at(), Some(0x08000002));
+    assert!(mock_data.completed());
+}
+
+#[test]
+fn download_dfuse_file_and_leave() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_leave(true);
+
+    let bytes = dfuse_file();
+    let file = DfuseFile::parse(&bytes).unwrap();
+    assert!(matches!(
+        dfu.download_dfuse_file(&file).unwrap(),
+        dfu_core::DownloadOutcome::Reset
+    ));
+    assert_eq!(mock_data.downloaded(), dfuse_file_memory());
+    assert_eq!(mock_data.jumped(), Some(0x08000002));
+}
+
+#[test]
+fn download_dfuse_file_missing_target() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .alt_setting(1)
+        .build();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    let bytes = dfuse_file();
+    let file = DfuseFile::parse(&bytes).unwrap();
+    assert!(matches!(
+        dfu.download_dfuse_file(&file),
+        Err(mock::Error::Dfu(dfu_core::Error::MissingDfuseTarget {
+            alt_setting: 1
+        }))
+    ));
+}
+
+#[test]
+fn download_dfuse_file_overlapping_elements() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    let bytes = Writer::new()
+        .add_target(
+            0,
+            None,
+            [
+                Element {
+                    address: 0x08000000,
+                    data: &[0; 8],
+                },
+                Element {
+                    address: 0x08000004,
+                    data: &[0; 8],
+                },
+            ],
+        )
+        .to_vec();
+    let file = DfuseFile::parse(&bytes).unwrap();
+    assert!(matches!(
+        dfu.download_dfuse_file(&file),
+        Err(mock::Error::Dfu(dfu_core::Error::OverlappingSegments {
+            address: 0x08000004
+        }))
+    ));
+}
+
+#[test]
+fn download_dfuse_file_element_out_of_range() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    // The second element is outside of the memory layout
+    let bytes = Writer::new()
+        .add_target(
+            0,
+            None,
+            [
+                Element {
+                    address: 0x08000000,
+                    data: &[0; 8],
+                },
+                Element {
+                    address: 0x08001000,
+                    data: &[0; 4],
+                },
+            ],
+        )
+        .to_vec();
+    let file = DfuseFile::parse(&bytes).unwrap();
+    assert!(matches!(
+        dfu.download_dfuse_file(&file),
+        Err(mock::Error::Dfu(dfu_core::Error::AddressOutOfRange {
+            address: 0x08001000,
+            length: 4,
+        }))
+    ));
+    assert!(mock_data.erased().is_empty());
+    assert!(mock_data.downloaded().is_empty());
+}
+
+#[test]
+fn download_intel_hex() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_verify(true);
+
+    dfu.download_intel_hex(&intel_hex()).unwrap();
+
+    assert_eq!(
+        mock_data.erased(),
+        &[(0, 4), (4, 4), (8, 4), (64, 8), (72, 8)]
+    );
+    assert_eq!(mock_data.downloaded(), dfuse_file_memory());
+    assert!(mock_data.completed());
+}
+
+#[test]
+fn download_intel_hex_and_leave() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_leave(true);
+
+    assert!(matches!(
+        dfu.download_intel_hex(&intel_hex()).unwrap(),
+        dfu_core::DownloadOutcome::Reset
+    ));
+    assert_eq!(mock_data.downloaded(), dfuse_file_memory());
+    assert_eq!(mock_data.jumped(), Some(0x08000199));
+}
+
+#[test]
+fn download_intel_hex_segment_not_writeable() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .memory_layout("16*4 g,8*8 c")
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    // The segment at 0x08000002 could be written, the other ones are read-only
+    assert!(matches!(
+        dfu.download_intel_hex(&intel_hex()),
+        Err(mock::Error::Dfu(dfu_core::Error::MissingPageAttribute {
+            address: 0x08000040,
+            attribute: dfu_core::memory_layout::Attributes::WRITEABLE,
+        }))
+    ));
+    assert!(mock_data.erased().is_empty());
+    assert!(mock_data.downloaded().is_empty());
+}
+
+#[test]
+fn download_intel_hex_not_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default().build();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    assert!(matches!(
+        dfu.download_intel_hex(&intel_hex()),
+        Err(mock::Error::Dfu(dfu_core::Error::DfuseNotSupported))
+    ));
+}
@@ -0,0 +1,240 @@
+use dfu_core::dfuse_file::{DfuseFile, Element, Writer};
+use dfu_core::intel_hex::IntelHex;
+use futures_test::test;
+
+mod mock;
+
+fn setup() {
+    let _ = env_logger::builder()
+        .is_test(true)
+        .filter_level(log::LevelFilter::Trace)
+        .parse_default_env()
+        .try_init();
+}
+
+#[test]
+async fn get_commands() {
+    setup();
+    let mock = mock::MockIOBuilder::default().dfuse(true).build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+
+    let commands = dfu.get_commands().await.unwrap();
+    assert!(commands.get && commands.set_address && commands.erase && commands.read_unprotect);
+    assert_eq!(mock_data.state(), dfu_core::State::DfuIdle);
+}
+
+#[test]
+async fn read_unprotect() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .memory(vec![0xaa; 16])
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+
+    dfu.read_unprotect().await.unwrap();
+    assert!(mock_data.disconnected());
+    assert!(mock_data.downloaded().is_empty());
+}
+
+#[test]
+async fn read_unprotect_status_error() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .vanish(std::io::ErrorKind::TimedOut)
+        .build();
+    let dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+
+    assert!(matches!(
+        dfu.read_unprotect().await,
+        Err(mock::Error::IO(err)) if err.kind() == std::io::ErrorKind::TimedOut
+    ));
+}
+
+#[test]
+async fn leave_status_error() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .vanish(std::io::ErrorKind::TimedOut)
+        .build();
+    let dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+
+    assert!(matches!(
+        dfu.leave(0x08000100).await,
+        Err(mock::Error::IO(err)) if err.kind() == std::io::ErrorKind::TimedOut
+    ));
+}
+
+#[test]
+async fn download_with_mass_erase() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .manifestation_tolerant(true)
+        .build();
+    let size = mock.size();
+    let firmware: Vec<u8> = (0..size).map(|i| i as u8).collect();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    dfu.with_mass_erase(true);
+
+    dfu.download_from_slice(&firmware).await.unwrap();
+    assert_eq!(mock_data.erased(), vec![(0, size)]);
+    assert_eq!(firmware, mock_data.downloaded());
+}
+
+#[test]
+async fn download_and_leave() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let firmware: Vec<u8> = (0..mock.size()).map(|i| i as u8).collect();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    dfu.with_leave(true);
+
+    assert!(matches!(
+        dfu.download_from_slice(&firmware).await.unwrap(),
+        dfu_core::DownloadOutcome::Reset
+    ));
+    assert_eq!(firmware, mock_data.downloaded());
+    assert_eq!(mock_data.jumped(), Some(0x08000000));
+}
+
+#[test]
+async fn download_to_page_not_erasable() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .memory_layout("15*4 g,1*4 e,8*8 g")
+        .build();
+    let firmware: Vec<u8> = (0..mock.size()).map(|i| i as u8).collect();
+    let mock_data = mock.data();
+    let dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+
+    assert!(matches!(
+        dfu.download_from_slice(&firmware).await,
+        Err(mock::Error::Dfu(dfu_core::Error::MissingPageAttribute {
+            address: 0x0800003c,
+            attribute: dfu_core::memory_layout::Attributes::ERASABLE,
+        }))
+    ));
+    assert!(mock_data.erased().is_empty());
+}
+
+#[test]
+async fn download_dfuse_file() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    dfu.with_verify(true);
+
+    let bytes = Writer::new()
+        .add_target(
+            0,
+            None,
+            [
+                Element {
+                    address: 0x08000046,
+                    data: &[0xc0; 10],
+                },
+                Element {
+                    address: 0x08000042,
+                    data: &[0xa0; 2],
+                },
+            ],
+        )
+        .to_vec();
+    let file = DfuseFile::parse(&bytes).unwrap();
+    dfu.download_dfuse_file(&file).await.unwrap();
+
+    assert_eq!(mock_data.erased(), &[(64, 8), (72, 8)]);
+    assert_eq!(
+        mock_data.downloaded(),
+        [vec![0xff; 66], vec![0xa0; 2], vec![0xff; 2], vec![0xc0; 10]].concat()
+    );
+    assert!(mock_data.completed());
+}
+
+#[test]
+async fn download_dfuse_file_element_out_of_range() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+
+    // The second element is outside of the memory layout
+    let bytes = Writer::new()
+        .add_target(
+            0,
+            None,
+            [
+                Element {
+                    address: 0x08000000,
+                    data: &[0; 8],
+                },
+                Element {
+                    address: 0x08001000,
+                    data: &[0; 4],
+                },
+            ],
+        )
+        .to_vec();
+    let file = DfuseFile::parse(&bytes).unwrap();
+    assert!(matches!(
+        dfu.download_dfuse_file(&file).await,
+        Err(mock::Error::Dfu(dfu_core::Error::AddressOutOfRange {
+            address: 0x08001000,
+            length: 4,
+        }))
+    ));
+    assert!(mock_data.erased().is_empty());
+    assert!(mock_data.downloaded().is_empty());
+}
+
+#[test]
+async fn download_intel_hex_and_leave() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    dfu.with_leave(true);
+
+    // Extended linear address 0x0800, 2 bytes at 0x42, start linear address 0x08000041
+    let hex = IntelHex::parse(
+        ":020000040800F2\n\
+         :02004200A0A07C\n\
+         :0400000508000041AE\n\
+         :00000001FF\n",
+    )
+    .unwrap();
+    assert!(matches!(
+        dfu.download_intel_hex(&hex).await.unwrap(),
+        dfu_core::DownloadOutcome::Reset
+    ));
+
+    assert_eq!(mock_data.erased(), &[(64, 8)]);
+    assert_eq!(
+        mock_data.downloaded(),
+        [vec![0xff; 66], vec![0xa0; 2]].concat()
+    );
+    assert_eq!(mock_data.jumped(), Some(0x08000041));
+}
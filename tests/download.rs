@@ -57,6 +57,12 @@ fn make_firmware(size: u32) -> Vec<u8> {
     firmware
 }
 
+fn with_suffix(firmware: &[u8]) -> Vec<u8> {
+    let mut file = firmware.to_vec();
+    dfu_core::suffix::Builder::new().append(&mut file);
+    file
+}
+
 fn test_simple_download(mock: MockIO) {
     let size = mock.size();
     let address = mock.address();
@@ -79,7 +85,10 @@ fn test_simple_download(mock: MockIO) {
         !descriptor.manifestation_tolerant && !descriptor.will_detach
     );
 
-    assert_eq!(mock_data.was_reset(), dfu.is_none());
+    assert_eq!(
+        mock_data.was_reset(),
+        matches!(dfu, dfu_core::DownloadOutcome::Reset)
+    );
     assert!(mock_data.completed());
     assert_eq!(firmware, mock_data.downloaded().as_slice());
 }
@@ -177,3 +186,588 @@ fn override_address_dfuse() {
         .build();
     test_simple_download(mock);
 }
+
+#[test]
+fn relative_layout_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .address(0x08000000)
+        .relative_layout(true)
+        .dfuse(true)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.override_address(0x08000000 + 60);
+
+    let firmware = make_firmware(8);
+    dfu.download_from_slice(&firmware).unwrap();
+
+    assert_eq!(mock_data.erased(), &[(60, 4), (64, 8)]);
+    assert_eq!(&mock_data.downloaded()[60..], firmware);
+}
+
+#[test]
+fn erase_from_address_dfuse() {
+    setup();
+    let memory = make_firmware(66);
+    let mock = mock::MockIOBuilder::default()
+        .address(0x08000000)
+        .memory(memory.clone())
+        .dfuse(true)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.override_address(0x08000000 + 66);
+
+    let firmware = make_firmware(20);
+    dfu.download_from_slice(&firmware).unwrap();
+
+    // Only the 8 bytes pages overlapping 66..86 are erased
+    assert_eq!(mock_data.erased(), &[(64, 8), (72, 8), (80, 8)]);
+    assert_eq!(mock_data.downloaded(), [memory, firmware].concat());
+}
+
+#[test]
+fn download_out_of_range_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .address(0x08000000)
+        .dfuse(true)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.override_address(0x08000000 + 120);
+
+    let res = dfu.download_from_slice(&make_firmware(16));
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::AddressOutOfRange {
+            address: 0x08000078,
+            length: 16,
+        }))
+    ));
+    assert!(mock_data.erased().is_empty());
+}
+
+#[test]
+fn download_file_with_suffix() {
+    setup();
+    let mock = mock::MockIOBuilder::default().build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+    let firmware = make_firmware(100);
+
+    dfu.download_file(&with_suffix(&firmware)).unwrap();
+
+    assert!(mock_data.completed());
+    assert_eq!(mock_data.downloaded(), firmware);
+}
+
+#[test]
+fn download_file_with_invalid_suffix() {
+    setup();
+    let mock = mock::MockIOBuilder::default().build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+    let mut file = with_suffix(&make_firmware(100));
+    file[0] ^= 0xff;
+
+    let res = dfu.download_file(&file);
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::Suffix(
+            dfu_core::suffix::Error::CrcMismatch { .. }
+        )))
+    ));
+    assert!(mock_data.downloaded().is_empty());
+}
+
+fn with_suffix_for(firmware: &[u8], vendor: u16, product: u16, device: u16) -> Vec<u8> {
+    let mut file = firmware.to_vec();
+    dfu_core::suffix::Builder::new()
+        .vendor(vendor)
+        .product(product)
+        .device(device)
+        .append(&mut file);
+    file
+}
+
+#[test]
+fn download_file_matching_device() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .device_id(0x0483, 0xdf11, 0x0200)
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+    let firmware = make_firmware(100);
+
+    // bcdDevice is a wildcard
+    dfu.download_file(&with_suffix_for(&firmware, 0x0483, 0xdf11, 0xffff))
+        .unwrap();
+
+    assert!(mock_data.completed());
+    assert_eq!(mock_data.downloaded(), firmware);
+}
+
+#[test]
+fn download_file_for_unknown_device() {
+    setup();
+    let mock = mock::MockIOBuilder::default().build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+    let file = with_suffix_for(&make_firmware(100), 0x0483, 0xdf11, 0xffff);
+
+    let res = dfu.download_file(&file);
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::Suffix(
+            dfu_core::suffix::Error::UnknownDevice {
+                vendor: 0x0483,
+                product: 0xdf11,
+            }
+        )))
+    ));
+    assert!(mock_data.downloaded().is_empty());
+}
+
+#[test]
+fn download_file_for_unknown_device_forced() {
+    setup();
+    let mock = mock::MockIOBuilder::default().build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_force(true);
+    let firmware = make_firmware(100);
+
+    dfu.download_file(&with_suffix_for(&firmware, 0x0483, 0xdf11, 0xffff))
+        .unwrap();
+
+    assert!(mock_data.completed());
+    assert_eq!(mock_data.downloaded(), firmware);
+}
+
+#[test]
+fn download_file_for_another_device() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .device_id(0x0483, 0xdf11, 0x0200)
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+    let file = with_suffix_for(&make_firmware(100), 0x0483, 0xdf12, 0x0200);
+
+    let res = dfu.download_file(&file);
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::Suffix(
+            dfu_core::suffix::Error::ProductMismatch {
+                file: 0xdf12,
+                device: 0xdf11,
+            }
+        )))
+    ));
+    assert!(mock_data.downloaded().is_empty());
+}
+
+#[test]
+fn download_file_for_another_device_forced() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .device_id(0x0483, 0xdf11, 0x0200)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_force(true);
+    let firmware = make_firmware(100);
+
+    dfu.download_file(&with_suffix_for(&firmware, 0x1209, 0x0001, 0x0100))
+        .unwrap();
+
+    assert!(mock_data.completed());
+    assert_eq!(mock_data.downloaded(), firmware);
+}
+
+fn test_verified_download(mock: MockIO) -> Result<(), mock::Error> {
+    let firmware = make_firmware(mock.size());
+    let cursor = TestCursor::new(&firmware);
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_verify(true);
+
+    dfu.download(cursor, firmware.len() as u32)?;
+
+    assert!(mock_data.completed());
+    assert_eq!(firmware, mock_data.downloaded().as_slice());
+    Ok(())
+}
+
+#[test]
+fn verify_manifestation_toleration() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .manifestation_tolerant(true)
+        .build();
+    test_verified_download(mock).unwrap();
+}
+
+#[test]
+fn verify_no_manifestation_toleration() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .manifestation_tolerant(false)
+        .build();
+    assert!(matches!(
+        test_verified_download(mock),
+        Err(mock::Error::Dfu(dfu_core::Error::VerificationNotSupported))
+    ));
+}
+
+#[test]
+fn verify_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .manifestation_tolerant(false)
+        .dfuse(true)
+        .build();
+    test_verified_download(mock).unwrap();
+}
+
+#[test]
+fn verify_mismatch() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .manifestation_tolerant(true)
+        .corrupted(42)
+        .build();
+    assert!(matches!(
+        test_verified_download(mock),
+        Err(mock::Error::Dfu(dfu_core::Error::VerificationFailed {
+            address: 42
+        }))
+    ));
+}
+
+#[test]
+fn verify_mismatch_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .corrupted(100)
+        .build();
+    assert!(matches!(
+        test_verified_download(mock),
+        Err(mock::Error::Dfu(dfu_core::Error::VerificationFailed {
+            address: 0x08000064
+        }))
+    ));
+}
+
+#[test]
+fn abort_download() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuDnloadIdle)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    dfu.abort().unwrap();
+    assert_eq!(mock_data.state(), dfu_core::State::DfuIdle);
+}
+
+#[test]
+fn status_and_state() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuDnloadIdle)
+        .build();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    assert_eq!(dfu.state().unwrap(), dfu_core::State::DfuDnloadIdle);
+    let status = dfu.status().unwrap();
+    assert_eq!(status.status, dfu_core::Status::Ok);
+    assert_eq!(status.state, dfu_core::State::DfuDnloadIdle);
+    assert_eq!(status.poll_timeout, 10);
+}
+
+#[test]
+fn download_from_error_state() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuError)
+        .status(dfu_core::Status::ErrWrite)
+        .manifestation_tolerant(true)
+        .build();
+    test_simple_download(mock);
+}
+
+#[test]
+fn download_from_stuck_error_state() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuError)
+        .status(dfu_core::Status::ErrWrite)
+        .stuck(true)
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    let res = dfu.download_from_slice(&make_firmware(16));
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::StatusError {
+            status: dfu_core::Status::ErrWrite,
+            state: dfu_core::State::DfuError,
+            index: 0,
+        }))
+    ));
+    assert!(mock_data.downloaded().is_empty());
+}
+
+#[test]
+fn download_from_stuck_download_idle() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuDnloadIdle)
+        .stuck(true)
+        .build();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    let res = dfu.download_from_slice(&make_firmware(16));
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::InvalidState {
+            got: dfu_core::State::DfuDnloadIdle,
+            expected: dfu_core::State::DfuIdle,
+        }))
+    ));
+}
+
+#[test]
+fn download_from_download_idle() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuDnloadIdle)
+        .manifestation_tolerant(true)
+        .build();
+    test_simple_download(mock);
+}
+
+#[test]
+fn download_from_upload_idle() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuUploadIdle)
+        .dfuse(true)
+        .build();
+    test_simple_download(mock);
+}
+
+#[test]
+fn download_from_manifest_sync() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuManifestSync)
+        .manifestation_tolerant(true)
+        .build();
+    test_simple_download(mock);
+}
+
+#[test]
+fn download_from_manifest_wait_reset() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuManifestWaitReset)
+        .build();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+    let res = dfu.download_from_slice(&make_firmware(16));
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::InvalidState {
+            got: dfu_core::State::DfuManifestWaitReset,
+            expected: dfu_core::State::DfuIdle,
+        }))
+    ));
+}
+
+fn test_download_from_app_idle(builder: mock::MockIOBuilder) {
+    let mock = builder.clone().state(dfu_core::State::AppIdle).build();
+    let firmware = make_firmware(mock.size());
+    let will_detach = mock.functional_descriptor().will_detach;
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.with_auto_detach(true);
+
+    let res = dfu.download_from_slice(&firmware);
+    assert!(matches!(res, Ok(dfu_core::DownloadOutcome::Detached)));
+    assert_eq!(mock_data.was_reset(), !will_detach);
+    assert_eq!(mock_data.detach_timeout(), Some(8));
+    assert_eq!(mock_data.state(), dfu_core::State::DfuIdle);
+
+    // The device is now in DFU mode
+    let mock = builder.reopen(mock_data.clone());
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.download_from_slice(&firmware).unwrap();
+    assert!(mock_data.completed());
+    assert_eq!(firmware, mock_data.downloaded().as_slice());
+}
+
+#[test]
+fn download_from_app_idle() {
+    setup();
+    let builder = mock::MockIOBuilder::default().manifestation_tolerant(true);
+    test_download_from_app_idle(builder);
+}
+
+#[test]
+fn download_from_app_idle_will_detach() {
+    setup();
+    let builder = mock::MockIOBuilder::default()
+        .manifestation_tolerant(true)
+        .will_detach(true)
+        .dfuse(true);
+    test_download_from_app_idle(builder);
+}
+
+#[test]
+fn download_from_app_idle_without_auto_detach() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::AppIdle)
+        .build();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+    let res = dfu.download_from_slice(&make_firmware(16));
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::InvalidState {
+            got: dfu_core::State::AppIdle,
+            expected: dfu_core::State::DfuIdle,
+        }))
+    ));
+}
+
+#[test]
+fn detach() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::AppIdle)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    assert!(dfu.detach().unwrap());
+    assert_eq!(mock_data.detach_timeout(), Some(8));
+    assert_eq!(mock_data.state(), dfu_core::State::AppDetach);
+    dfu.usb_reset().unwrap();
+    assert_eq!(mock_data.state(), dfu_core::State::DfuIdle);
+}
+
+#[test]
+fn detach_will_detach() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::AppIdle)
+        .will_detach(true)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    assert!(!dfu.detach().unwrap());
+    assert_eq!(mock_data.state(), dfu_core::State::DfuIdle);
+}
+
+#[test]
+fn detach_timeout() {
+    setup();
+    for (timeout, expected) in [(5, 5), (100, 8)] {
+        let mock = mock::MockIOBuilder::default()
+            .state(dfu_core::State::AppIdle)
+            .build();
+        let mock_data = mock.data();
+        let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+        dfu.with_detach_timeout(timeout);
+
+        dfu.detach().unwrap();
+        assert_eq!(mock_data.detach_timeout(), Some(expected));
+    }
+}
+
+#[test]
+fn detach_not_app_idle() {
+    setup();
+    let mock = mock::MockIOBuilder::default().build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    assert!(matches!(
+        dfu.detach(),
+        Err(mock::Error::Dfu(dfu_core::Error::InvalidState {
+            got: dfu_core::State::DfuIdle,
+            expected: dfu_core::State::AppIdle,
+        }))
+    ));
+    assert_eq!(mock_data.detach_timeout(), None);
+}
+
+#[test]
+fn download_status_error() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .download_error(dfu_core::Status::ErrWrite, 4)
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    let res = dfu.download_from_slice(&make_firmware(16));
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::StatusError {
+            status: dfu_core::Status::ErrWrite,
+            state: dfu_core::State::DfuError,
+            index: 4,
+        }))
+    ));
+    assert_eq!(mock_data.state(), dfu_core::State::DfuError);
+}
+
+#[test]
+fn download_status_error_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .download_error(dfu_core::Status::ErrErase, 0)
+        .build();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    let res = dfu.download_from_slice(&make_firmware(16));
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::StatusError {
+            status: dfu_core::Status::ErrErase,
+            state: dfu_core::State::DfuError,
+            index: 0,
+        }))
+    ));
+}
+
+#[test]
+fn download_status_error_description() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .download_error(dfu_core::Status::ErrVendor, 4)
+        .string_descriptor(4, "flash locked")
+        .build();
+    let dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    let res = dfu.download_from_slice(&make_firmware(16));
+    match res {
+        Err(mock::Error::Dfu(dfu_core::Error::StatusErrorDescription {
+            status: dfu_core::Status::ErrVendor,
+            state: dfu_core::State::DfuError,
+            description,
+        })) => assert_eq!(description, "flash locked"),
+        _ => panic!("unexpected result"),
+    }
+}
@@ -84,7 +84,10 @@ async fn test_simple_download(mock: MockIO) {
         mock_data.was_reset(),
         !descriptor.manifestation_tolerant && !descriptor.will_detach
     );
-    assert_eq!(mock_data.was_reset(), dfu.is_none());
+    assert_eq!(
+        mock_data.was_reset(),
+        matches!(dfu, dfu_core::DownloadOutcome::Reset)
+    );
     assert!(mock_data.completed());
     assert_eq!(firmware, mock_data.downloaded().as_slice());
 }
@@ -204,3 +207,257 @@ async fn override_address_dfuse() {
         .build();
     test_simple_download(mock).await;
 }
+
+#[test]
+async fn erase_from_address_dfuse() {
+    setup();
+    let memory = make_firmware(66);
+    let mock = mock::MockIOBuilder::default()
+        .address(0x08000000)
+        .memory(memory.clone())
+        .dfuse(true)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    dfu.override_address(0x08000000 + 66);
+
+    let firmware = make_firmware(20);
+    dfu.download_from_slice(&firmware).await.unwrap();
+
+    // Only the 8 bytes pages overlapping 66..86 are erased
+    assert_eq!(mock_data.erased(), &[(64, 8), (72, 8), (80, 8)]);
+    assert_eq!(mock_data.downloaded(), [memory, firmware].concat());
+}
+
+#[test]
+async fn download_out_of_range_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .address(0x08000000)
+        .dfuse(true)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    dfu.override_address(0x08000000 + 120);
+
+    let res = dfu.download_from_slice(&make_firmware(16)).await;
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::AddressOutOfRange {
+            address: 0x08000078,
+            length: 16,
+        }))
+    ));
+    assert!(mock_data.erased().is_empty());
+}
+
+#[test]
+async fn download_file_with_suffix() {
+    setup();
+    let mock = mock::MockIOBuilder::default().build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    let firmware = make_firmware(100);
+    let mut file = firmware.clone();
+    dfu_core::suffix::Builder::new().append(&mut file);
+
+    dfu.download_file(&file).await.unwrap();
+
+    assert!(mock_data.completed());
+    assert_eq!(mock_data.downloaded(), firmware);
+}
+
+#[test]
+async fn download_file_for_another_device() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .device_id(0x0483, 0xdf11, 0x0200)
+        .build();
+    let mock_data = mock.data();
+    let dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    let mut file = make_firmware(100);
+    dfu_core::suffix::Builder::new()
+        .vendor(0x1209)
+        .append(&mut file);
+
+    let res = dfu.download_file(&file).await;
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::Suffix(
+            dfu_core::suffix::Error::VendorMismatch { .. }
+        )))
+    ));
+    assert!(mock_data.downloaded().is_empty());
+}
+
+async fn test_verified_download(mock: MockIO) -> Result<(), mock::Error> {
+    let firmware = make_firmware(mock.size());
+    let cursor = TestCursor::new(&firmware);
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    dfu.with_verify(true);
+
+    dfu.download(cursor, firmware.len() as u32).await?;
+
+    assert!(mock_data.completed());
+    assert_eq!(firmware, mock_data.downloaded().as_slice());
+    Ok(())
+}
+
+#[test]
+async fn verify_manifestation_toleration() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .manifestation_tolerant(true)
+        .build();
+    test_verified_download(mock).await.unwrap();
+}
+
+#[test]
+async fn verify_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .manifestation_tolerant(false)
+        .dfuse(true)
+        .build();
+    test_verified_download(mock).await.unwrap();
+}
+
+#[test]
+async fn verify_mismatch_dfuse() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .address(0x08000000)
+        .corrupted(100)
+        .build();
+    assert!(matches!(
+        test_verified_download(mock).await,
+        Err(mock::Error::Dfu(dfu_core::Error::VerificationFailed {
+            address: 0x08000064
+        }))
+    ));
+}
+
+#[test]
+async fn status_and_state() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuDnloadIdle)
+        .build();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+
+    assert_eq!(dfu.state().await.unwrap(), dfu_core::State::DfuDnloadIdle);
+    let status = dfu.status().await.unwrap();
+    assert_eq!(status.status, dfu_core::Status::Ok);
+    assert_eq!(status.state, dfu_core::State::DfuDnloadIdle);
+    assert_eq!(status.poll_timeout, 10);
+}
+
+#[test]
+async fn download_from_error_state() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuError)
+        .status(dfu_core::Status::ErrWrite)
+        .manifestation_tolerant(true)
+        .build();
+    test_simple_download(mock).await;
+}
+
+#[test]
+async fn download_from_download_idle() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuDnloadIdle)
+        .dfuse(true)
+        .build();
+    test_simple_download(mock).await;
+}
+
+#[test]
+async fn download_from_app_idle() {
+    setup();
+    let builder = mock::MockIOBuilder::default().manifestation_tolerant(true);
+    let mock = builder.clone().state(dfu_core::State::AppIdle).build();
+    let firmware = make_firmware(mock.size());
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    dfu.with_auto_detach(true);
+
+    let res = dfu
+        .download(TestCursor::new(&firmware), firmware.len() as u32)
+        .await;
+    assert!(matches!(res, Ok(dfu_core::DownloadOutcome::Detached)));
+    assert!(mock_data.was_reset());
+    assert_eq!(mock_data.state(), dfu_core::State::DfuIdle);
+
+    let mock = builder.reopen(mock_data.clone());
+    let dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    dfu.download(TestCursor::new(&firmware), firmware.len() as u32)
+        .await
+        .unwrap();
+    assert!(mock_data.completed());
+    assert_eq!(firmware, mock_data.downloaded().as_slice());
+}
+
+#[test]
+async fn detach() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::AppIdle)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    dfu.with_detach_timeout(5);
+
+    assert!(dfu.detach().await.unwrap());
+    assert_eq!(mock_data.detach_timeout(), Some(5));
+    dfu.usb_reset().await.unwrap();
+    assert_eq!(mock_data.state(), dfu_core::State::DfuIdle);
+}
+
+#[test]
+async fn download_status_error() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .download_error(dfu_core::Status::ErrWrite, 4)
+        .build();
+    let firmware = make_firmware(16);
+    let dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+
+    let res = dfu
+        .download(TestCursor::new(&firmware), firmware.len() as u32)
+        .await;
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::StatusError {
+            status: dfu_core::Status::ErrWrite,
+            state: dfu_core::State::DfuError,
+            index: 4,
+        }))
+    ));
+}
+
+#[test]
+async fn download_status_error_description() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .download_error(dfu_core::Status::ErrVendor, 4)
+        .string_descriptor(4, "flash locked")
+        .build();
+    let firmware = make_firmware(16);
+    let dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+
+    let res = dfu
+        .download(TestCursor::new(&firmware), firmware.len() as u32)
+        .await;
+    match res {
+        Err(mock::Error::Dfu(dfu_core::Error::StatusErrorDescription {
+            status: dfu_core::Status::ErrVendor,
+            state: dfu_core::State::DfuError,
+            description,
+        })) => assert_eq!(description, "flash locked"),
+        _ => panic!("unexpected result"),
+    }
+}
@@ -1,3 +1,6 @@
+// Shared between test binaries, not all of them use every helper.
+#![allow(dead_code)]
+
 use std::{
     convert::TryFrom,
     sync::{Arc, Mutex, MutexGuard},
@@ -5,8 +8,8 @@ use std::{
 
 use bytes::{Buf, BufMut};
 use dfu_core::{
-    functional_descriptor::FunctionalDescriptor, memory_layout::MemoryLayout, DfuIo, DfuProtocol,
-    State, Status,
+    functional_descriptor::FunctionalDescriptor, memory_layout::MemoryLayout, suffix::DeviceId,
+    DfuIo, DfuProtocol, State, Status,
 };
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
@@ -29,6 +32,9 @@ enum Request {
 // dfu-core does not set the direction so read/write aren't distinguished
 const REQUEST_TYPE: u8 = 0b00100001;
 
+// DfuSe commands supported: get, set address, erase and read unprotect
+const COMMANDS: [u8; 4] = [0x00, 0x21, 0x41, 0x92];
+
 #[derive(Debug, Clone, Default)]
 pub struct MockIOBuilder {
     manifestation_tolerant: bool,
@@ -36,6 +42,18 @@ pub struct MockIOBuilder {
     // STM dfu extensions (dfuse)
     dfuse: bool,
     address: Option<u32>,
+    memory_layout: Option<String>,
+    relative_layout: bool,
+    alt_setting: u8,
+    device_id: Option<DeviceId>,
+    vanish: Option<std::io::ErrorKind>,
+    memory: Vec<u8>,
+    corrupted: Option<usize>,
+    state: Option<State>,
+    status: Option<Status>,
+    download_error: Option<(Status, u8)>,
+    stuck: bool,
+    strings: Vec<(u8, String)>,
 }
 
 impl MockIOBuilder {
@@ -59,23 +77,121 @@ impl MockIOBuilder {
         self
     }
 
+    /// DfuSe memory layout, 16 pages of 4 bytes and 8 pages of 8 bytes by default.
+    pub fn memory_layout(mut self, memory_layout: &str) -> Self {
+        self.memory_layout = Some(memory_layout.to_string());
+        self
+    }
+
+    /// Alternate setting of the DFU interface.
+    /// Build the memory layout with `MemoryLayout::try_from`, its pages starting at 0.
+    pub fn relative_layout(mut self, relative_layout: bool) -> Self {
+        self.relative_layout = relative_layout;
+        self
+    }
+
+    pub fn alt_setting(mut self, alt_setting: u8) -> Self {
+        self.alt_setting = alt_setting;
+        self
+    }
+
+    // The device resets or jumps without answering, failing the status request with this error
+    pub fn vanish(mut self, kind: std::io::ErrorKind) -> Self {
+        self.vanish = Some(kind);
+        self
+    }
+
+    pub fn device_id(mut self, vendor: u16, product: u16, device: u16) -> Self {
+        self.device_id = Some(DeviceId {
+            vendor,
+            product,
+            device,
+        });
+        self
+    }
+
+    pub fn memory(mut self, memory: Vec<u8>) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Corrupt the byte at this offset when reading it back.
+    pub fn corrupted(mut self, offset: usize) -> Self {
+        self.corrupted = Some(offset);
+        self
+    }
+
+    /// Initial state of the device.
+    pub fn state(mut self, state: State) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Initial status of the device.
+    pub fn status(mut self, status: Status) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Fail the first download block with this status and iString index.
+    pub fn download_error(mut self, status: Status, index: u8) -> Self {
+        self.download_error = Some((status, index));
+        self
+    }
+
+    /// String descriptor at this index.
+    /// The device ignores the requests to clear its status and to abort.
+    pub fn stuck(mut self, stuck: bool) -> Self {
+        self.stuck = stuck;
+        self
+    }
+
+    pub fn string_descriptor(mut self, index: u8, string: &str) -> Self {
+        self.strings.push((index, string.to_string()));
+        self
+    }
+
     pub fn build(self) -> MockIO {
+        let data = MockIOData::new();
+        {
+            let mut inner = data.inner();
+            inner.download = self.memory.clone();
+            inner.corrupted = self.corrupted;
+            if let Some(state) = self.state {
+                inner.state = state;
+            }
+            if let Some(status) = self.status {
+                inner.status = status;
+            }
+            inner.download_error = self.download_error;
+            inner.stuck = self.stuck;
+        }
+        self.reopen(data)
+    }
+
+    /// Open the device again, keeping its state, as done after a detach.
+    pub fn reopen(self, data: MockIOData) -> MockIO {
+        let memory_layout = self.memory_layout.as_deref().unwrap_or("16*4 g,8*8 g");
         let (dfu_version, protocol) = if !self.dfuse {
             ((0x1, 0x10), DfuProtocol::Dfu)
         } else {
             (
                 (0x1, 0x1a),
                 DfuProtocol::Dfuse {
-                    address: 0x0,
-                    // 16 pages of 4 bytes; 8 pages of 8 bytes;
-                    memory_layout: MemoryLayout::try_from("16*4 g,8*8 g").unwrap(),
+                    address: self.address.unwrap_or(0x0),
+                    memory_layout: if self.relative_layout {
+                        MemoryLayout::try_from(memory_layout)
+                    } else {
+                        MemoryLayout::parse(self.address.unwrap_or(0x0), memory_layout)
+                    }
+                    .unwrap(),
                 },
             )
         };
 
         let functional_descriptor = FunctionalDescriptor {
             can_download: true,
-            can_upload: false,
+            can_upload: true,
             manifestation_tolerant: self.manifestation_tolerant,
             will_detach: self.will_detach,
             detach_timeout: 8,
@@ -83,14 +199,18 @@ impl MockIOBuilder {
             dfu_version,
         };
 
-        let data = MockIOData::new();
         let address = self.address;
 
         MockIO {
             functional_descriptor,
             protocol,
+            alt_setting: self.alt_setting,
+            device_id: self.device_id,
+            vanish: self.vanish,
+            strings: self.strings,
             data,
             address,
+            relative_layout: self.relative_layout,
         }
     }
 }
@@ -105,6 +225,20 @@ struct MockIOInner {
     busy: u16,
     was_reset: bool,
     saw_incomplete_write: bool,
+    address_pointer: u32,
+    upload_pos: usize,
+    corrupted: Option<usize>,
+    detach_timeout: Option<u16>,
+    download_error: Option<(Status, u8)>,
+    stuck: bool,
+    i_string: u8,
+    get_commands: bool,
+    read_unprotect: bool,
+    disconnected: bool,
+    leave_address: Option<u32>,
+    jumped: Option<u32>,
+    set_address: Option<u32>,
+    manifested_at: Option<u32>,
 }
 
 #[derive(Debug, Clone)]
@@ -121,6 +255,20 @@ impl MockIOData {
             busy: 0,
             was_reset: false,
             saw_incomplete_write: false,
+            address_pointer: 0,
+            upload_pos: 0,
+            corrupted: None,
+            detach_timeout: None,
+            download_error: None,
+            stuck: false,
+            i_string: 0,
+            get_commands: false,
+            read_unprotect: false,
+            disconnected: false,
+            leave_address: None,
+            jumped: None,
+            set_address: None,
+            manifested_at: None,
         })))
     }
 
@@ -139,6 +287,35 @@ impl MockIOData {
         )
     }
 
+    pub fn state(&self) -> State {
+        self.inner().state
+    }
+
+    /// Timeout of the last detach request.
+    pub fn detach_timeout(&self) -> Option<u16> {
+        self.inner().detach_timeout
+    }
+
+    /// The device reset by itself and is no longer reachable.
+    pub fn disconnected(&self) -> bool {
+        self.inner().disconnected
+    }
+
+    /// Erased pages as address and size.
+    pub fn erased(&self) -> Vec<(u32, u32)> {
+        self.inner().erased.clone()
+    }
+
+    /// Address the device jumped to when leaving DFU mode.
+    pub fn jumped(&self) -> Option<u32> {
+        self.inner().jumped
+    }
+
+    /// Last address set before the device manifested.
+    pub fn manifested_at(&self) -> Option<u32> {
+        self.inner().manifested_at
+    }
+
     pub fn downloaded(&self) -> Vec<u8> {
         self.inner().download.clone()
     }
@@ -147,8 +324,13 @@ impl MockIOData {
 pub struct MockIO {
     functional_descriptor: FunctionalDescriptor,
     protocol: DfuProtocol<MemoryLayout>,
+    alt_setting: u8,
+    device_id: Option<DeviceId>,
+    vanish: Option<std::io::ErrorKind>,
+    strings: Vec<(u8, String)>,
     data: MockIOData,
     address: Option<u32>,
+    relative_layout: bool,
 }
 
 impl MockIO {
@@ -157,7 +339,7 @@ impl MockIO {
             DfuProtocol::Dfu => 128,
             DfuProtocol::Dfuse {
                 ref memory_layout, ..
-            } => memory_layout.iter().sum(),
+            } => memory_layout.iter().map(|page| page.size).sum(),
         }
     }
 
@@ -181,21 +363,30 @@ impl MockIO {
             } => memory_layout,
         };
 
-        let mut offset = address;
-        let page_size = m
+        let offset = self.translate_address(address);
+        let page_address = if self.relative_layout {
+            offset
+        } else {
+            address
+        };
+        let page = m
             .iter()
-            .copied()
-            .find(|&page| match offset {
-                0 => true,
-                _ if offset >= page => {
-                    offset -= page;
-                    false
-                }
-                _ => panic!("erase not at page boundary, address: {}", address),
-            })
+            .find(|page| page.contains(page_address))
             .expect("Trying to erase after flash");
+        assert_eq!(
+            page.address, page_address,
+            "erase not at page boundary, address: {}",
+            address
+        );
+        assert!(page.attributes.is_erasable(), "erase of a protected page");
 
-        self.inner().erased.push((address, page_size));
+        let mut inner = self.inner();
+        assert!(
+            !inner.erased.contains(&(offset, page.size)),
+            "page erased twice, address: {}",
+            address
+        );
+        inner.erased.push((offset, page.size));
     }
 
     fn state(&self) -> State {
@@ -222,7 +413,7 @@ impl MockIO {
         buffer[0] = self.status().into(); // status ok
         (&mut buffer[1..]).put_uint_le(10, 3); // idle time
         buffer[4] = state.into();
-        buffer[5] = 0; // iString descriptor
+        buffer[5] = self.inner().i_string; // iString descriptor
         Ok(6)
     }
 
@@ -264,22 +455,55 @@ impl MockIO {
     fn download_request_dfuse(&self, blocknum: u16, buffer: &[u8]) {
         match blocknum {
             0 => match buffer[0] {
+                0x00 => {
+                    // get commands
+                    assert_eq!(buffer.len(), 1);
+                    self.inner().get_commands = true;
+                }
                 0x21 => {
                     // set address
                     let addr = buffer[1..].as_ref().get_u32_le();
-                    let addr = self.translate_address(addr);
-                    assert_eq!(addr, self.inner().download.len() as u32);
+                    let mut inner = self.inner();
+                    inner.leave_address = Some(addr);
+                    inner.set_address = Some(addr);
+                    inner.address_pointer = self.translate_address(addr);
+                }
+                0x92 => {
+                    // read unprotect
+                    assert_eq!(buffer.len(), 1);
+                    self.inner().read_unprotect = true;
+                }
+                0x41 if buffer.len() == 1 => {
+                    // mass erase, takes a while
+                    let size = self.size();
+                    let mut inner = self.inner();
+                    inner.download.clear();
+                    inner.erased = vec![(0, size)];
+                    inner.busy = 5;
                 }
                 0x41 => {
                     // erase page
                     let addr = buffer[1..].as_ref().get_u32_le();
-                    let addr = self.translate_address(addr);
                     self.erase_page(addr);
                 }
                 cmd => todo!("Command not supported: {}", cmd),
             },
             1 => panic!("STM reserved block"),
             _ => {
+                let addr = self.block_address(self.inner().address_pointer, blocknum);
+                if blocknum == 2 {
+                    // A new segment starts, the memory in between stays erased
+                    let mut inner = self.inner();
+                    assert!(
+                        addr as usize >= inner.download.len(),
+                        "Overwriting downloaded data at {}",
+                        addr
+                    );
+                    inner.download.resize(addr as usize, 0xff);
+                    inner.writes = 0;
+                    inner.saw_incomplete_write = false;
+                }
+                assert_eq!(addr, self.inner().download.len() as u32);
                 self.check_erasures(buffer);
                 self.download_request_dfu(blocknum - 2, buffer)
             }
@@ -287,12 +511,72 @@ impl MockIO {
     }
 
     fn download_request(&self, blocknum: u16, buffer: &[u8]) {
+        if buffer.first() != Some(&0x21) || blocknum != 0 {
+            self.inner().leave_address = None;
+        }
         match self.protocol {
             DfuProtocol::Dfu => self.download_request_dfu(blocknum, buffer),
             DfuProtocol::Dfuse { .. } => self.download_request_dfuse(blocknum, buffer),
         }
     }
 
+    fn block_address(&self, address_pointer: u32, blocknum: u16) -> u32 {
+        address_pointer + (blocknum as u32 - 2) * self.functional_descriptor.transfer_size as u32
+    }
+
+    fn upload_request(&self, blocknum: u16, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut inner = self.inner();
+        if blocknum == 0 && matches!(self.protocol, DfuProtocol::Dfuse { .. }) {
+            assert!(
+                std::mem::take(&mut inner.get_commands),
+                "Upload of commands without a get command"
+            );
+            let len = COMMANDS.len().min(buffer.len());
+            buffer[..len].copy_from_slice(&COMMANDS[..len]);
+            inner.state = if len < buffer.len() {
+                State::DfuIdle
+            } else {
+                State::DfuUploadIdle
+            };
+            return Ok(len);
+        }
+        let start = match self.protocol {
+            DfuProtocol::Dfu => {
+                assert_eq!(
+                    inner.upload_pos,
+                    blocknum as usize * self.functional_descriptor.transfer_size as usize
+                );
+                inner.upload_pos
+            }
+            DfuProtocol::Dfuse { .. } => {
+                assert!(blocknum >= 2, "Unexpected upload block: {}", blocknum);
+                self.block_address(inner.address_pointer, blocknum) as usize
+            }
+        };
+        let start = start.min(inner.download.len());
+        let len = buffer.len().min(inner.download.len() - start);
+        buffer[..len].copy_from_slice(&inner.download[start..start + len]);
+        if let Some(offset) = inner.corrupted.filter(|o| (start..start + len).contains(o)) {
+            buffer[offset - start] ^= 0xff;
+        }
+        if len < buffer.len() {
+            inner.upload_pos = 0;
+            inner.state = State::DfuIdle;
+        } else {
+            inner.upload_pos = start + len;
+            inner.state = State::DfuUploadIdle;
+        }
+        Ok(len)
+    }
+
+    fn check_connected(&self) -> Result<(), Error> {
+        if self.inner().disconnected {
+            Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into())
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn busy_cycles(&self, cycles: u16) {
         self.inner().busy = cycles;
     }
@@ -332,7 +616,19 @@ impl DfuIo for MockIO {
     ) -> Result<Self::Read, Self::Error> {
         assert_eq!(request_type, REQUEST_TYPE);
         let request = Request::from_u8(request).expect("Unknown request");
+        self.check_connected()?;
         match (request, self.state()) {
+            (Request::DFU_GETSTATUS, State::DfuDnloadSync) if self.inner().read_unprotect => {
+                // The memory is erased and the device resets
+                let mut inner = self.inner();
+                inner.download.clear();
+                inner.disconnected = true;
+                drop(inner);
+                if let Some(kind) = self.vanish {
+                    return Err(std::io::Error::from(kind).into());
+                }
+                self.status_request(buffer, State::DfuDnbusy)
+            }
             (Request::DFU_GETSTATUS, State::DfuDnloadSync) => {
                 if self.still_busy() {
                     self.status_request(buffer, State::DfuDnbusy)
@@ -341,6 +637,13 @@ impl DfuIo for MockIO {
                     self.status_request(buffer, State::DfuDnloadIdle)
                 }
             }
+            (Request::DFU_GETSTATUS, State::DfuManifestSync) if self.inner().jumped.is_some() => {
+                self.inner().disconnected = true;
+                if let Some(kind) = self.vanish {
+                    return Err(std::io::Error::from(kind).into());
+                }
+                self.status_request(buffer, State::DfuManifest)
+            }
             (Request::DFU_GETSTATUS, State::DfuManifestSync) => {
                 if !self.functional_descriptor.manifestation_tolerant {
                     self.update_state(State::DfuManifestWaitReset);
@@ -356,6 +659,15 @@ impl DfuIo for MockIO {
                 assert_eq!(value, 0);
                 self.status_request(buffer, self.state())
             }
+            (Request::DFU_GETSTATE, state) => {
+                assert_eq!(value, 0);
+                assert_eq!(buffer.len(), 1);
+                buffer[0] = state.into();
+                Ok(1)
+            }
+            (Request::DFU_UPLOAD, State::DfuIdle | State::DfuUploadIdle) => {
+                self.upload_request(value, buffer)
+            }
             (request, state) => panic!(
                 "Unexpected read request: {:?} in state {:?}",
                 request, state
@@ -372,18 +684,55 @@ impl DfuIo for MockIO {
     ) -> Result<Self::Write, Self::Error> {
         assert_eq!(request_type, REQUEST_TYPE);
         let request = Request::from_u8(request).expect("Unknown request");
+        self.check_connected()?;
         match (request, self.state()) {
             (Request::DFU_DNLOAD, State::DfuIdle | State::DfuDnloadIdle) => {
+                let download_error = self.inner().download_error.take();
                 if buffer.is_empty() {
                     assert_eq!(self.state(), State::DfuDnloadIdle);
+                    // A leave request sets the address then sends block 2 without data
+                    let mut inner = self.inner();
+                    inner.jumped = inner.leave_address.take().filter(|_| value == 2);
+                    inner.manifested_at = inner.set_address;
+                    drop(inner);
                     self.busy_cycles(3);
                     self.update_state(State::DfuManifestSync);
+                } else if let Some((status, index)) = download_error {
+                    let mut inner = self.inner();
+                    inner.status = status;
+                    inner.i_string = index;
+                    inner.state = State::DfuError;
                 } else {
                     self.update_state(State::DfuDnloadSync);
                     self.download_request(value, buffer);
                 }
                 Ok(buffer.len())
             }
+            (Request::DFU_DETACH, State::AppIdle) => {
+                let mut inner = self.inner();
+                inner.detach_timeout = Some(value);
+                // A device that detaches by itself comes back in DFU mode right away
+                inner.state = if self.functional_descriptor.will_detach {
+                    State::DfuIdle
+                } else {
+                    State::AppDetach
+                };
+                Ok(0)
+            }
+            (Request::DFU_CLRSTATUS | Request::DFU_ABORT, _) if self.inner().stuck => Ok(0),
+            (Request::DFU_CLRSTATUS, State::DfuError) => {
+                let mut inner = self.inner();
+                inner.status = Status::Ok;
+                inner.i_string = 0;
+                inner.state = State::DfuIdle;
+                Ok(0)
+            }
+            (Request::DFU_ABORT, State::DfuIdle | State::DfuDnloadIdle | State::DfuUploadIdle) => {
+                let mut inner = self.inner();
+                inner.upload_pos = 0;
+                inner.state = State::DfuIdle;
+                Ok(0)
+            }
             (request, state) => panic!(
                 "Unexpected write request: {:?} in state {:?}",
                 request, state
@@ -393,12 +742,11 @@ impl DfuIo for MockIO {
 
     fn usb_reset(self) -> Result<Self::Reset, Self::Error> {
         self.inner().was_reset = true;
-        assert_eq!(
-            self.state(),
-            State::DfuManifestWaitReset,
-            "Wrong state for reset: {:?}",
-            self.state()
-        );
+        match self.state() {
+            State::DfuManifestWaitReset => {}
+            State::AppDetach => self.update_state(State::DfuIdle),
+            state => panic!("Wrong state for reset: {:?}", state),
+        }
         assert!(!self.functional_descriptor.will_detach, "Unexpected Reset");
         Ok(())
     }
@@ -410,6 +758,26 @@ impl DfuIo for MockIO {
     fn protocol(&self) -> &dfu_core::DfuProtocol<Self::MemoryLayout> {
         &self.protocol
     }
+
+    fn alt_setting(&self) -> u8 {
+        self.alt_setting
+    }
+
+    fn device_id(&self) -> Option<DeviceId> {
+        self.device_id
+    }
+
+    fn is_disconnected(&self, error: &Self::Error) -> bool {
+        matches!(error, Error::IO(err) if err.kind() == std::io::ErrorKind::NotConnected)
+    }
+
+    fn get_string_descriptor(&self, index: u8) -> Result<Option<String>, Self::Error> {
+        Ok(self
+            .strings
+            .iter()
+            .find(|(i, _)| *i == index)
+            .map(|(_, s)| s.clone()))
+    }
 }
 
 #[cfg(feature = "async")]
@@ -453,4 +821,20 @@ impl dfu_core::asynchronous::DfuAsyncIo for MockIO {
     fn protocol(&self) -> &dfu_core::DfuProtocol<Self::MemoryLayout> {
         DfuIo::protocol(self)
     }
+
+    fn alt_setting(&self) -> u8 {
+        DfuIo::alt_setting(self)
+    }
+
+    fn device_id(&self) -> Option<DeviceId> {
+        DfuIo::device_id(self)
+    }
+
+    fn is_disconnected(&self, error: &Self::Error) -> bool {
+        DfuIo::is_disconnected(self, error)
+    }
+
+    async fn get_string_descriptor(&self, index: u8) -> Result<Option<String>, Self::Error> {
+        DfuIo::get_string_descriptor(self, index)
+    }
 }
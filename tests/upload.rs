@@ -0,0 +1,157 @@
+use mock::MockIO;
+
+mod mock;
+
+fn setup() {
+    let _ = env_logger::builder()
+        .is_test(true)
+        .filter_level(log::LevelFilter::Trace)
+        .parse_default_env()
+        .try_init();
+}
+
+fn make_firmware(size: u32) -> Vec<u8> {
+    let mut firmware = Vec::with_capacity(size as usize);
+    for i in 0..size {
+        firmware.push(i as u8);
+    }
+    firmware
+}
+
+fn test_simple_upload(mock: MockIO, firmware: &[u8], address: Option<u32>, max_len: u32) {
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    if let Some(address) = address {
+        dfu.override_address(address);
+    }
+
+    let mut uploaded = Vec::new();
+    let n = dfu.upload(&mut uploaded, max_len).unwrap();
+
+    assert_eq!(n as usize, uploaded.len());
+    assert_eq!(firmware, uploaded.as_slice());
+    assert!(mock_data.completed());
+}
+
+#[test]
+fn upload_until_short_frame() {
+    setup();
+    let firmware = make_firmware(128);
+    let mock = mock::MockIOBuilder::default()
+        .memory(firmware.clone())
+        .build();
+    test_simple_upload(mock, &firmware, None, u32::MAX);
+}
+
+#[test]
+fn upload_multiple_of_transfer_size() {
+    setup();
+    let firmware = make_firmware(6 * 20);
+    let mock = mock::MockIOBuilder::default()
+        .memory(firmware.clone())
+        .build();
+    test_simple_upload(mock, &firmware, None, u32::MAX);
+}
+
+#[test]
+fn upload_max_len() {
+    setup();
+    let firmware = make_firmware(128);
+    let mock = mock::MockIOBuilder::default()
+        .memory(firmware.clone())
+        .build();
+    test_simple_upload(mock, &firmware[..50], None, 50);
+}
+
+#[test]
+fn upload_dfuse() {
+    setup();
+    let firmware = make_firmware(128);
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .memory(firmware.clone())
+        .build();
+    test_simple_upload(mock, &firmware, None, 128);
+}
+
+#[test]
+fn upload_dfuse_from_address() {
+    setup();
+    let firmware = make_firmware(128);
+    let mock = mock::MockIOBuilder::default()
+        .address(0x08004000)
+        .dfuse(true)
+        .memory(firmware.clone())
+        .build();
+    test_simple_upload(mock, &firmware[0x10..0x3a], Some(0x08004010), 0x2a);
+}
+
+#[test]
+fn upload_dfuse_relative_layout() {
+    setup();
+    let firmware = make_firmware(128);
+    let mock = mock::MockIOBuilder::default()
+        .address(0x08004000)
+        .relative_layout(true)
+        .dfuse(true)
+        .memory(firmware.clone())
+        .build();
+    test_simple_upload(mock, &firmware[0x10..0x3a], Some(0x08004010), 0x2a);
+}
+
+#[test]
+fn upload_dfuse_out_of_range() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .address(0x08004000)
+        .dfuse(true)
+        .build();
+    let size = mock.size();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.override_address(0x08004010);
+
+    let res = dfu.upload(Vec::new(), size);
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::AddressOutOfRange { .. }))
+    ));
+}
+
+#[test]
+fn upload_dfuse_not_readable() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .address(0x08000000)
+        .memory_layout("16*4 g,8*8 f")
+        .dfuse(true)
+        .build();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+    dfu.override_address(0x08000038);
+
+    let res = dfu.upload(Vec::new(), 16);
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::MissingPageAttribute {
+            address: 0x08000040,
+            attribute: dfu_core::memory_layout::Attributes::READABLE,
+        }))
+    ));
+}
+
+#[test]
+fn abort_upload() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuUploadIdle)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::synchronous::DfuSync::new(mock);
+
+    dfu.abort().unwrap();
+    assert_eq!(mock_data.state(), dfu_core::State::DfuIdle);
+
+    // Aborting an idle device does nothing
+    dfu.abort().unwrap();
+    assert_eq!(mock_data.state(), dfu_core::State::DfuIdle);
+}
@@ -0,0 +1,121 @@
+use futures_test::test;
+use mock::MockIO;
+
+mod mock;
+
+fn setup() {
+    let _ = env_logger::builder()
+        .is_test(true)
+        .filter_level(log::LevelFilter::Trace)
+        .parse_default_env()
+        .try_init();
+}
+
+fn make_firmware(size: u32) -> Vec<u8> {
+    let mut firmware = Vec::with_capacity(size as usize);
+    for i in 0..size {
+        firmware.push(i as u8);
+    }
+    firmware
+}
+
+async fn test_simple_upload(mock: MockIO, firmware: &[u8], address: Option<u32>, max_len: u32) {
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+
+    if let Some(address) = address {
+        dfu.override_address(address);
+    }
+
+    let mut uploaded = futures::io::Cursor::new(Vec::new());
+    let n = dfu.upload(&mut uploaded, max_len).await.unwrap();
+
+    let uploaded = uploaded.into_inner();
+    assert_eq!(n as usize, uploaded.len());
+    assert_eq!(firmware, uploaded.as_slice());
+    assert!(mock_data.completed());
+}
+
+#[test]
+async fn upload_until_short_frame() {
+    setup();
+    let firmware = make_firmware(128);
+    let mock = mock::MockIOBuilder::default()
+        .memory(firmware.clone())
+        .build();
+    test_simple_upload(mock, &firmware, None, u32::MAX).await;
+}
+
+#[test]
+async fn upload_multiple_of_transfer_size() {
+    setup();
+    let firmware = make_firmware(6 * 20);
+    let mock = mock::MockIOBuilder::default()
+        .memory(firmware.clone())
+        .build();
+    test_simple_upload(mock, &firmware, None, u32::MAX).await;
+}
+
+#[test]
+async fn upload_max_len() {
+    setup();
+    let firmware = make_firmware(128);
+    let mock = mock::MockIOBuilder::default()
+        .memory(firmware.clone())
+        .build();
+    test_simple_upload(mock, &firmware[..50], None, 50).await;
+}
+
+#[test]
+async fn upload_dfuse() {
+    setup();
+    let firmware = make_firmware(128);
+    let mock = mock::MockIOBuilder::default()
+        .dfuse(true)
+        .memory(firmware.clone())
+        .build();
+    test_simple_upload(mock, &firmware, None, 128).await;
+}
+
+#[test]
+async fn upload_dfuse_from_address() {
+    setup();
+    let firmware = make_firmware(128);
+    let mock = mock::MockIOBuilder::default()
+        .address(0x08004000)
+        .dfuse(true)
+        .memory(firmware.clone())
+        .build();
+    test_simple_upload(mock, &firmware[0x10..0x3a], Some(0x08004010), 0x2a).await;
+}
+
+#[test]
+async fn upload_dfuse_out_of_range() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .address(0x08004000)
+        .dfuse(true)
+        .build();
+    let size = mock.size();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+    dfu.override_address(0x08004010);
+
+    let res = dfu.upload(futures::io::sink(), size).await;
+    assert!(matches!(
+        res,
+        Err(mock::Error::Dfu(dfu_core::Error::AddressOutOfRange { .. }))
+    ));
+}
+
+#[test]
+async fn abort_upload() {
+    setup();
+    let mock = mock::MockIOBuilder::default()
+        .state(dfu_core::State::DfuUploadIdle)
+        .build();
+    let mock_data = mock.data();
+    let mut dfu = dfu_core::asynchronous::DfuAsync::new(mock);
+
+    dfu.abort().await.unwrap();
+    assert_eq!(mock_data.state(), dfu_core::State::DfuIdle);
+}